@@ -1,7 +1,17 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
-use gossip_glomers::{InitBody, Message, MessageID, Node, Output};
+use anyhow::{Context, bail};
+use gossip_glomers::{
+    InitBody, Message, MessageID, Node, Output,
+    cluster::{Cluster, Topology},
+    crdt::GSet,
+    detector::{Detection, FailureDetector},
+    gossip::{self, Gossip, GossipMessage},
+    limit::{Limits, Rate},
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
@@ -14,17 +24,21 @@ struct TopologyBody {
     topology: HashMap<String, Vec<String>>,
 }
 
-#[derive(Debug, Serialize, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 struct ReadOkBody {
     messages: HashSet<u64>,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+/// Named after the request each answers; the wire types carry the `_ok`.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type")]
 enum ResponseType {
-    BroadcastOk,
-    ReadOk(ReadOkBody),
-    TopologyOk,
+    #[serde(rename = "broadcast_ok")]
+    Broadcast,
+    #[serde(rename = "read_ok")]
+    Read(ReadOkBody),
+    #[serde(rename = "topology_ok")]
+    Topology,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
@@ -33,6 +47,166 @@ enum MessageType {
     Broadcast(BroadcastBody),
     Read,
     Topology(TopologyBody),
+    /// Keeps an otherwise quiet link from looking dead.
+    Ping,
+    #[serde(untagged)]
+    Gossip(GossipMessage<GSet<u64>>),
+}
+
+/// Gossip and ping intervals are rounded up to a whole number of ticks.
+const TICK_INTERVAL: Duration = Duration::from_millis(20);
+/// How long a neighbour may stay silent before it is suspected.
+const SUSPECT_AFTER: Duration = Duration::from_secs(1);
+/// Gossip, acks and pings all show a neighbour is alive, so a link only
+/// needs a ping once it has carried nothing for this long. Half of
+/// `SUSPECT_AFTER` leaves room for the ping to land before that runs out.
+const PING_AFTER: Duration = Duration::from_millis(500);
+/// Twice what a link carries at the default gossip interval. Anything over
+/// it is resent next round anyway, so a node gossiping faster than that
+/// falls back to the rate instead of flooding its neighbours.
+const GOSSIP_RATE: Rate = Rate {
+    per_second: 20.0,
+    burst: 10.0,
+};
+/// A ping only goes out on a link that has been quiet for `PING_AFTER`.
+const PING_RATE: Rate = Rate {
+    per_second: 4.0,
+    burst: 2.0,
+};
+/// Client requests that may wait to be handled before new broadcasts are
+/// turned away. Reads are cheap, so they always queue.
+const INBOUND_CAPACITY: usize = 1_000;
+
+/// Knobs that trade how quickly a value reaches every node against how many
+/// messages the nodes send to get it there.
+#[derive(Clone, Debug, PartialEq)]
+struct Tuning {
+    /// How long new values wait to be batched into one message per
+    /// neighbour.
+    gossip_interval: Duration,
+    /// How many neighbours each round goes to.
+    fanout: usize,
+    /// Who to gossip with: this layout of the cluster, or whatever
+    /// Maelstrom's `topology` message says if `None`.
+    topology: Option<Topology>,
+    /// How often to log messages per operation to stderr.
+    report_interval: Option<Duration>,
+}
+
+/// A tree with four children per node keeps 25 nodes within five hops of
+/// each other over 24 links, and no node has more than five neighbours, so
+/// a fanout of five still reaches all of them every round.
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            gossip_interval: Duration::from_millis(100),
+            fanout: 5,
+            topology: Some(Topology::Tree(4)),
+            report_interval: None,
+        }
+    }
+}
+
+impl Tuning {
+    /// Reads `--gossip-interval-ms`, `--fanout`, `--topology` (`total`,
+    /// `line`, `ring`, `grid`, `tree:<children>` or `maelstrom`) and
+    /// `--report-interval-ms`, each defaulting as above.
+    fn from_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut tuning = Tuning::default();
+        while let Some(arg) = args.next() {
+            let Some(value) = args.next() else {
+                bail!("{arg} needs a value");
+            };
+            let millis = || -> anyhow::Result<Duration> {
+                Ok(Duration::from_millis(
+                    value.parse().with_context(|| format!("{arg} {value}"))?,
+                ))
+            };
+
+            match arg.as_str() {
+                "--gossip-interval-ms" => tuning.gossip_interval = millis()?,
+                "--fanout" => {
+                    tuning.fanout = value.parse().context("--fanout")?;
+                    if tuning.fanout == 0 {
+                        bail!("--fanout must be at least 1");
+                    }
+                }
+                "--report-interval-ms" => tuning.report_interval = Some(millis()?),
+                "--topology" => {
+                    tuning.topology = match value.as_str() {
+                        "maelstrom" => None,
+                        "total" => Some(Topology::Total),
+                        "line" => Some(Topology::Line),
+                        "ring" => Some(Topology::Ring),
+                        "grid" => Some(Topology::Grid),
+                        tree => match tree.strip_prefix("tree:").map(str::parse) {
+                            Some(Ok(children)) => Some(Topology::Tree(children)),
+                            _ => bail!("unknown topology {value}"),
+                        },
+                    }
+                }
+                _ => bail!("unexpected argument {arg}"),
+            }
+        }
+        Ok(tuning)
+    }
+}
+
+/// Messages sent to other nodes against client operations served, the
+/// ratio Maelstrom reports as msgs-per-op. Maelstrom also counts the client
+/// messages themselves, which this leaves out.
+struct Report {
+    interval: Duration,
+    last: Instant,
+    operations: u64,
+    /// Messages the throttle let through, by `type`.
+    messages: BTreeMap<&'static str, u64>,
+}
+
+impl Report {
+    fn due(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.last) < self.interval {
+            return false;
+        }
+        self.last = now;
+        true
+    }
+
+    fn sent(&mut self, kind: &'static str) {
+        *self.messages.entry(kind).or_default() += 1;
+    }
+
+    fn summary(&self) -> String {
+        let total: u64 = self.messages.values().sum();
+        let kinds: Vec<String> = self
+            .messages
+            .iter()
+            .map(|(kind, count)| format!("{kind} {count}"))
+            .collect();
+
+        format!(
+            "{total} messages to other nodes ({}) for {} operations, {:.2} msgs-per-op",
+            kinds.join(", "),
+            self.operations,
+            total as f64 / self.operations.max(1) as f64,
+        )
+    }
+}
+
+/// The `type` a gossip message goes out as.
+fn kind<S>(message: &GossipMessage<S>) -> &'static str {
+    match message {
+        GossipMessage::Push(_) => "gossip",
+        GossipMessage::Pull => "gossip_pull",
+        GossipMessage::PushPull(_) => "gossip_push_pull",
+        GossipMessage::Delta(_) => "gossip_delta",
+        GossipMessage::Ack(_) => "gossip_ack",
+    }
+}
+
+/// Values only need passing on when some node is not our neighbour.
+fn relays(cluster: &Cluster, neighbours: &[String]) -> bool {
+    cluster.peers().any(|peer| !neighbours.contains(peer))
 }
 
 type MessageBody = gossip_glomers::MessageBody<MessageType>;
@@ -43,83 +217,175 @@ type Response = gossip_glomers::Response<ResponseType>;
 
 struct BroadcastNode {
     msg_id: MessageID,
-    node_id: String,
+    cluster: Cluster,
+    tuning: Tuning,
     neighbours: Vec<String>,
-    messages: HashSet<u64>,
+    gossip: Gossip<GSet<u64>>,
+    detector: FailureDetector,
+    report: Option<Report>,
+}
+
+impl BroadcastNode {
+    /// Gossips only with the neighbours that look alive; anything a suspected
+    /// neighbour misses is caught up by the first round after it is back.
+    fn refresh_peers(&mut self) {
+        let peers = self
+            .neighbours
+            .iter()
+            .filter(|n| self.detector.is_alive(n))
+            .cloned()
+            .collect();
+        self.gossip.set_peers(peers);
+    }
+
+    fn send_gossip(
+        &mut self,
+        messages: Vec<(String, GossipMessage<GSet<u64>>)>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        for (peer, gossip) in messages {
+            self.detector.sent_to(&peer, Instant::now());
+
+            let sent = kind(&gossip);
+            let message = Message {
+                src: self.cluster.node_id().to_string(),
+                dst: peer,
+                body: MessageBody {
+                    kind: MessageType::Gossip(gossip),
+                    msg_id: None,
+                },
+            };
+            if output
+                .send(&message)
+                .context("serializing gossip message")?
+                && let Some(report) = &mut self.report
+            {
+                report.sent(sent);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Node<MessageType> for BroadcastNode {
-    fn init(message: InitBody) -> Self {
+    const TICK_INTERVAL: Option<Duration> = Some(TICK_INTERVAL);
+
+    type Config = Tuning;
+
+    fn init(message: InitBody, tuning: Tuning) -> Self {
+        let cluster = Cluster::from(message);
+        let neighbours = cluster.neighbours(tuning.topology.unwrap_or(Topology::Total));
+        let now = Instant::now();
+
         Self {
             msg_id: 1,
-            node_id: message.node_id.clone(),
-            neighbours: message
-                .node_ids
-                .iter()
-                .filter(|&n| n != &message.node_id)
-                .cloned()
-                .collect(),
-            messages: HashSet::new(),
+            // Delta mode batches every value that is new to a neighbour into
+            // one message per round, and resends until it is acknowledged.
+            gossip: Gossip::new(
+                &cluster,
+                neighbours.clone(),
+                GSet::default(),
+                gossip::Config {
+                    interval: tuning.gossip_interval,
+                    fanout: tuning.fanout,
+                    mode: gossip::Mode::Delta,
+                    relay: relays(&cluster, &neighbours),
+                    ..gossip::Config::default()
+                },
+                now,
+            ),
+            detector: FailureDetector::new(
+                &cluster,
+                PING_AFTER,
+                Detection::Timeout(SUSPECT_AFTER),
+                now,
+            ),
+            report: tuning.report_interval.map(|interval| Report {
+                interval,
+                last: now,
+                operations: 0,
+                messages: BTreeMap::new(),
+            }),
+            neighbours,
+            cluster,
+            tuning,
         }
     }
 
-    fn on_message(&mut self, message: Message<MessageType>, output: &mut Output) -> anyhow::Result<()> {
-        match message.body.kind {
-            MessageType::Broadcast(body) => {
-                if !self.messages.insert(body.message) {
-                    return Ok(());
-                }
+    fn limits() -> Limits {
+        Limits {
+            outbound: HashMap::from([
+                ("gossip_delta".to_string(), GOSSIP_RATE),
+                ("gossip_ack".to_string(), GOSSIP_RATE),
+                ("ping".to_string(), PING_RATE),
+            ]),
+            inbound_capacity: Some(INBOUND_CAPACITY),
+            shed: HashSet::from(["broadcast".to_string()]),
+            ..Limits::default()
+        }
+    }
 
-                for neighbour in &self.neighbours {
-                    let reply = Message {
-                        src: self.node_id.clone(),
-                        dst: neighbour.clone(),
-                        body: MessageBody {
-                            kind: MessageType::Broadcast(BroadcastBody {
-                                message: body.message,
-                            }),
-                            msg_id: None,
-                        },
-                    };
+    fn on_message(
+        &mut self,
+        message: Message<MessageType>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        if self
+            .detector
+            .heard_from(&message.src, Instant::now())
+            .is_some()
+        {
+            self.refresh_peers();
+        }
 
-                    reply
-                        .serialize(&mut *output)
-                        .context("serializing broadcast_ok response")?;
+        if let (Some(report), MessageType::Broadcast(_) | MessageType::Read) =
+            (&mut self.report, &message.body.kind)
+        {
+            report.operations += 1;
+        }
+
+        match message.body.kind {
+            MessageType::Broadcast(body) => {
+                if self.gossip.update(|messages| messages.insert(body.message)) {
+                    output
+                        .persist(&serde_json::to_vec(&body.message)?)
+                        .context("persisting broadcast message")?;
                 }
 
                 if message.body.msg_id.is_some() {
                     let reply = Response {
-                        src: self.node_id.clone(),
+                        src: self.cluster.node_id().to_string(),
                         dst: message.src,
                         body: ResponseBody {
-                            kind: ResponseType::BroadcastOk,
+                            kind: ResponseType::Broadcast,
                             msg_id: Some(self.msg_id),
                             in_reply_to: message.body.msg_id,
                         },
                     };
-                    reply
-                        .serialize(output)
+                    output
+                        .send(&reply)
                         .context("serializing broadcast_ok response")?;
                     self.msg_id += 1;
                 }
 
                 Ok(())
-            },
+            }
             MessageType::Read => {
                 let reply = Response {
-                    src: self.node_id.clone(),
+                    src: self.cluster.node_id().to_string(),
                     dst: message.src,
                     body: ResponseBody {
-                        kind: ResponseType::ReadOk(ReadOkBody {
-                            messages: self.messages.clone(),
+                        kind: ResponseType::Read(ReadOkBody {
+                            messages: self.gossip.state().elements().clone(),
                         }),
                         msg_id: Some(self.msg_id),
                         in_reply_to: message.body.msg_id,
                     },
                 };
 
-                reply
-                    .serialize(output)
+                output
+                    .send(&reply)
                     .context("serializing read_ok response")?;
                 self.msg_id += 1;
 
@@ -127,30 +393,286 @@ impl Node<MessageType> for BroadcastNode {
             }
             MessageType::Topology(body) => {
                 let reply = Response {
-                    src: self.node_id.clone(),
+                    src: self.cluster.node_id().to_string(),
                     dst: message.src,
                     body: ResponseBody {
-                        kind: ResponseType::TopologyOk,
+                        kind: ResponseType::Topology,
                         msg_id: Some(self.msg_id),
                         in_reply_to: message.body.msg_id,
                     },
                 };
 
-                if let Some(neighbours) = body.topology.get(&self.node_id) {
-                    self.neighbours = neighbours.clone()
+                if self.tuning.topology.is_none()
+                    && let Some(neighbours) = body.topology.get(self.cluster.node_id())
+                {
+                    self.neighbours = neighbours.clone();
+                    self.gossip
+                        .set_relay(relays(&self.cluster, &self.neighbours));
+                    self.refresh_peers();
                 }
 
-                reply
-                    .serialize(output)
+                output
+                    .send(&reply)
                     .context("serializing topology_ok response")?;
                 self.msg_id += 1;
 
                 Ok(())
             }
+            MessageType::Ping => Ok(()),
+            MessageType::Gossip(kind) => {
+                let incoming = match &kind {
+                    GossipMessage::Push(body) | GossipMessage::PushPull(body) => Some(&body.state),
+                    GossipMessage::Delta(body) => Some(&body.state),
+                    GossipMessage::Pull | GossipMessage::Ack(_) => None,
+                };
+                if let Some(incoming) = incoming {
+                    for value in incoming
+                        .elements()
+                        .difference(self.gossip.state().elements())
+                    {
+                        output
+                            .persist(&serde_json::to_vec(value)?)
+                            .context("persisting broadcast message")?;
+                    }
+                }
+
+                let replies = self.gossip.on_message(&message.src, kind);
+                self.send_gossip(replies, output)
+            }
         }
     }
+
+    fn on_tick(&mut self, output: &mut Output) -> anyhow::Result<()> {
+        let now = Instant::now();
+
+        if !self.detector.check(now).is_empty() {
+            self.refresh_peers();
+        }
+
+        let messages = self.gossip.tick(now);
+        self.send_gossip(messages, output)?;
+
+        // Only neighbours are gossiped with, so only they need watching.
+        let pings: Vec<String> = self
+            .detector
+            .pings_due(now)
+            .into_iter()
+            .filter(|peer| self.neighbours.contains(peer))
+            .collect();
+        for peer in pings {
+            let ping = Message {
+                src: self.cluster.node_id().to_string(),
+                dst: peer,
+                body: MessageBody {
+                    kind: MessageType::Ping,
+                    msg_id: None,
+                },
+            };
+            if output.send(&ping).context("serializing ping")?
+                && let Some(report) = &mut self.report
+            {
+                report.sent("ping");
+            }
+        }
+
+        if let Some(report) = &mut self.report
+            && report.due(now)
+        {
+            eprintln!("{}: {}", self.cluster.node_id(), report.summary());
+        }
+
+        Ok(())
+    }
+
+    fn replay(&mut self, record: &[u8]) -> anyhow::Result<()> {
+        self.gossip
+            .state_mut()
+            .insert(serde_json::from_slice(record)?);
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(self.gossip.state()).expect("a set of integers always serializes")
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()> {
+        *self.gossip.state_mut() = serde_json::from_slice(snapshot)?;
+
+        Ok(())
+    }
 }
 
 pub fn main() -> anyhow::Result<()> {
-    gossip_glomers::run::<BroadcastNode, MessageType>()
+    let tuning = Tuning::from_args(std::env::args().skip(1))?;
+
+    gossip_glomers::run::<BroadcastNode, MessageType>(tuning)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gossip_glomers::codec::round_trip;
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Broadcast(BroadcastBody { message: 1 }),
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Read,
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Message {
+            src: "n2".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Ping,
+                msg_id: None,
+            },
+        });
+        round_trip(Message {
+            src: "n2".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Gossip(GossipMessage::Push(gossip::GossipBody {
+                    state: GSet::from(HashSet::from([1, 2])),
+                })),
+                msg_id: None,
+            },
+        });
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Topology(TopologyBody {
+                    topology: HashMap::from([("n1".to_string(), vec!["n2".to_string()])]),
+                }),
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::Broadcast,
+                msg_id: Some(1),
+                in_reply_to: Some(1),
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::Read(ReadOkBody {
+                    messages: HashSet::from([1, 2, 3]),
+                }),
+                msg_id: Some(1),
+                in_reply_to: Some(1),
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::Topology,
+                msg_id: Some(1),
+                in_reply_to: Some(1),
+            },
+        });
+
+        for (kind, wire) in [
+            (ResponseType::Broadcast, "broadcast_ok"),
+            (ResponseType::Topology, "topology_ok"),
+        ] {
+            assert_eq!(
+                serde_json::to_value(kind).unwrap(),
+                serde_json::json!({"type": wire})
+            );
+        }
+    }
+
+    #[test]
+    fn test_tuning_from_args() {
+        let args = |args: &[&str]| Tuning::from_args(args.iter().map(|a| a.to_string()));
+
+        assert_eq!(args(&[]).unwrap(), Tuning::default());
+        assert_eq!(
+            args(&[
+                "--gossip-interval-ms",
+                "500",
+                "--topology",
+                "tree:4",
+                "--report-interval-ms",
+                "1000",
+            ])
+            .unwrap(),
+            Tuning {
+                gossip_interval: Duration::from_millis(500),
+                topology: Some(Topology::Tree(4)),
+                report_interval: Some(Duration::from_secs(1)),
+                ..Tuning::default()
+            }
+        );
+        assert_eq!(args(&["--topology", "maelstrom"]).unwrap().topology, None);
+        assert!(args(&["--topology", "tree"]).is_err());
+        assert!(args(&["--fanout"]).is_err());
+        assert!(args(&["--fanout", "0"]).is_err());
+        assert!(args(&["--batch", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_limits_cover_all_peer_traffic() {
+        let limits = BroadcastNode::limits();
+
+        // Delta mode sends only deltas and acks.
+        let gossip: [GossipMessage<GSet<u64>>; 2] = [
+            GossipMessage::Delta(gossip::DeltaBody {
+                state: GSet::default(),
+                seq: 1,
+                ack: None,
+            }),
+            GossipMessage::Ack(gossip::AckBody { seq: 1 }),
+        ];
+        for sent in gossip.iter().map(kind).chain(["ping"]) {
+            assert!(limits.outbound.contains_key(sent), "{sent} is unlimited");
+        }
+
+        assert!(limits.inbound_capacity.is_some());
+        assert!(limits.shed.contains("broadcast"));
+        assert!(!limits.shed.contains("read"));
+    }
+
+    #[test]
+    fn test_report_breaks_down_by_kind() {
+        let mut report = Report {
+            interval: Duration::from_secs(1),
+            last: Instant::now(),
+            operations: 4,
+            messages: BTreeMap::new(),
+        };
+        for sent in [
+            "gossip_delta",
+            "gossip_ack",
+            "gossip_delta",
+            "ping",
+            "gossip_delta",
+        ] {
+            report.sent(sent);
+        }
+
+        assert_eq!(
+            report.summary(),
+            "5 messages to other nodes (gossip_ack 1, gossip_delta 3, ping 1) for 4 operations, 1.25 msgs-per-op"
+        );
+    }
 }
@@ -14,12 +14,12 @@ enum MessageType {
     Echo(EchoBody),
 }
 
-#[derive(Debug, Serialize, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 struct EchoOkBody {
     echo: String,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ResponseType {
     EchoOk(EchoOkBody),
@@ -38,14 +38,20 @@ struct EchoNode {
 }
 
 impl Node<MessageType> for EchoNode {
-    fn init(message: InitBody) -> Self {
+    type Config = ();
+
+    fn init(message: InitBody, _config: ()) -> Self {
         Self {
             msg_id: 1,
             node_id: message.node_id,
         }
     }
 
-    fn on_message(&mut self, message: Message<MessageType>, output: &mut Output) -> anyhow::Result<()> {
+    fn on_message(
+        &mut self,
+        message: Message<MessageType>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
         match message.body.kind {
             MessageType::Echo(body) => {
                 let reply = Response {
@@ -58,8 +64,8 @@ impl Node<MessageType> for EchoNode {
                     },
                 };
 
-                reply
-                    .serialize(output)
+                output
+                    .send(&reply)
                     .context("serializing echo_ok response")?;
                 self.msg_id += 1;
 
@@ -70,7 +76,7 @@ impl Node<MessageType> for EchoNode {
 }
 
 pub fn main() -> anyhow::Result<()> {
-    gossip_glomers::run::<EchoNode, MessageType>()?;
+    gossip_glomers::run::<EchoNode, MessageType>(())?;
 
     Ok(())
 }
@@ -78,6 +84,7 @@ pub fn main() -> anyhow::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use gossip_glomers::codec::round_trip;
 
     #[test]
     fn test_echo_deserialize() {
@@ -140,4 +147,29 @@ mod tests {
             .unwrap()
         );
     }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Echo(EchoBody {
+                    echo: "foo".to_string(),
+                }),
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::EchoOk(EchoOkBody {
+                    echo: "foo".to_string(),
+                }),
+                msg_id: Some(1),
+                in_reply_to: Some(1),
+            },
+        });
+    }
 }
@@ -1,30 +1,75 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     time::{Duration, Instant},
 };
 
-use anyhow::Context;
-use gossip_glomers::{InitBody, Message, MessageID, Node, Output};
+use anyhow::{Context, bail};
+use gossip_glomers::{
+    ErrorBody, ErrorMessageType, InitBody, Message, MessageID, MessageIds, Node, Output, Service,
+    cluster::Cluster,
+    crdt::{GCounter, Merge},
+    gossip::{self, Gossip, GossipMessage},
+    kv::{
+        self, KvClient, KvError, Outcome, Progress, ReadId, Reply, Retry, Sharded, UpdateId,
+        Updates,
+    },
+    limit::{Limits, Rate},
+};
 use serde::{Deserialize, Serialize};
 
-const BROADCAST_INTERVAL: Duration = Duration::from_millis(500);
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(500);
+/// Often enough for seq-kv retries to back off briefly; gossip still only
+/// goes out every [`GOSSIP_INTERVAL`].
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// Several times the one delta and one ack per link every
+/// [`GOSSIP_INTERVAL`]; a dropped delta is resent until it is acknowledged.
+const GOSSIP_RATE: Rate = Rate {
+    per_second: 10.0,
+    burst: 5.0,
+};
+/// Client requests that may wait to be handled before new ones are turned
+/// away.
+const INBOUND_CAPACITY: usize = 1_000;
+
+/// Set from the command line before the runtime starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum BackendKind {
+    #[default]
+    Gossip,
+    SeqKv,
+}
+
+impl BackendKind {
+    /// Reads `--backend gossip` or `--backend seq-kv`, defaulting to gossip.
+    fn from_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut backend = BackendKind::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--backend" => {
+                    backend = match args.next().as_deref() {
+                        Some("gossip") => BackendKind::Gossip,
+                        Some("seq-kv") => BackendKind::SeqKv,
+                        other => bail!("unknown backend {other:?}, expected gossip or seq-kv"),
+                    }
+                }
+                other => bail!("unexpected argument {other:?}"),
+            }
+        }
+        Ok(backend)
+    }
+}
 
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 struct AddBody {
     delta: u64,
 }
 
-#[derive(Debug, Serialize, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 struct ReadOkBody {
     value: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-struct BroadcastBody {
-    values: HashMap<String, u64>,
-}
-
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ResponseType {
     AddOk,
@@ -36,7 +81,8 @@ enum ResponseType {
 enum MessageType {
     Add(AddBody),
     Read,
-    Broadcast(BroadcastBody),
+    #[serde(untagged)]
+    Gossip(GossipMessage<GCounter>),
 }
 
 type MessageBody = gossip_glomers::MessageBody<MessageType>;
@@ -45,25 +91,307 @@ type ResponseBody = gossip_glomers::ResponseBody<ResponseType>;
 
 type Response = gossip_glomers::Response<ResponseType>;
 
+/// A client request the seq-kv backend has finished with.
+#[derive(Debug, PartialEq)]
+struct Answer {
+    client: String,
+    in_reply_to: Option<MessageID>,
+    result: Result<ResponseType, KvError>,
+}
+
+/// Who to answer once a request is done.
+type Waiter = (String, Option<MessageID>);
+
+/// Keeps the counter in seq-kv rather than on the nodes: each node adds to
+/// its own shard, so only its own concurrent adds contend, and a read sums
+/// every node's shard.
+///
+/// seq-kv may serve a node a stale view, so a read first writes to the
+/// node's sync key, which brings its view up to date, and reads the shards
+/// only after that write is acknowledged. Partitions between nodes don't
+/// matter, since the nodes never talk to each other.
+struct KvCounter<K = kv::Sequential> {
+    client: K,
+    updates: Updates<u64>,
+    total: Sharded<u64>,
+    adds: HashMap<UpdateId, Waiter>,
+    /// Reads waiting on their sync write, by its msg_id.
+    syncing: HashMap<MessageID, Waiter>,
+    reads: HashMap<ReadId, Waiter>,
+    syncs: u64,
+}
+
+impl KvCounter {
+    fn new(cluster: &Cluster, ids: MessageIds) -> Self {
+        Self::with_client(
+            cluster,
+            kv::Sequential::new(cluster.node_id().to_string(), ids),
+        )
+    }
+}
+
+impl<K: KvClient> KvCounter<K> {
+    fn with_client(cluster: &Cluster, client: K) -> Self {
+        Self {
+            client,
+            updates: Updates::new(Retry::default()),
+            total: Sharded::per_node("counter", cluster, 0, |a, b| a + b),
+            adds: HashMap::new(),
+            syncing: HashMap::new(),
+            reads: HashMap::new(),
+            syncs: 0,
+        }
+    }
+
+    fn add(
+        &mut self,
+        node_id: &str,
+        client: String,
+        in_reply_to: Option<MessageID>,
+        delta: u64,
+    ) -> Message<kv::MessageType<u64>> {
+        let (id, message) =
+            self.total
+                .update(&mut self.updates, &mut self.client, node_id, move |n| {
+                    n + delta
+                });
+        self.adds.insert(id, (client, in_reply_to));
+        message
+    }
+
+    fn read(
+        &mut self,
+        node_id: &str,
+        client: String,
+        in_reply_to: Option<MessageID>,
+    ) -> Message<kv::MessageType<u64>> {
+        self.syncs += 1;
+        let message = self.client.write(&format!("sync-{node_id}"), self.syncs);
+        self.syncing.insert(
+            message.body.msg_id.expect("client sets msg_id"),
+            (client, in_reply_to),
+        );
+        message
+    }
+
+    fn on_service(
+        &mut self,
+        response: gossip_glomers::Response<kv::ResponseType>,
+        now: Instant,
+    ) -> (Vec<Message<kv::MessageType<u64>>>, Vec<Answer>) {
+        match self.client.resolve(response) {
+            Some(outcome) => self.on_outcome(outcome, now),
+            None => (Vec::new(), Vec::new()),
+        }
+    }
+
+    fn tick(&mut self, now: Instant) -> (Vec<Message<kv::MessageType<u64>>>, Vec<Answer>) {
+        let mut messages = Vec::new();
+        let mut answers = Vec::new();
+        for outcome in self.client.expire(now) {
+            let (more, done) = self.on_outcome(outcome, now);
+            messages.extend(more);
+            answers.extend(done);
+        }
+        messages.extend(self.updates.tick(&mut self.client, now));
+
+        (messages, answers)
+    }
+
+    fn on_outcome(
+        &mut self,
+        outcome: Outcome<u64>,
+        now: Instant,
+    ) -> (Vec<Message<kv::MessageType<u64>>>, Vec<Answer>) {
+        let answer = |(client, in_reply_to): Waiter, result| Answer {
+            client,
+            in_reply_to,
+            result,
+        };
+
+        if self.updates.owns(outcome.msg_id) {
+            return match self.updates.on_outcome(&mut self.client, outcome, now) {
+                Some(Progress::Send(message)) => (vec![message], Vec::new()),
+                Some(Progress::Done(updated)) => {
+                    let answers = self
+                        .adds
+                        .remove(&updated.id)
+                        .map(|waiter| answer(waiter, updated.result.map(|_| ResponseType::AddOk)))
+                        .into_iter()
+                        .collect();
+                    (Vec::new(), answers)
+                }
+                Some(Progress::Backoff) | None => (Vec::new(), Vec::new()),
+            };
+        }
+
+        if let Some(waiter) = self.syncing.remove(&outcome.msg_id) {
+            return match outcome.result {
+                Ok(Reply::Written) => {
+                    let (id, messages) = self.total.read(&mut self.client);
+                    self.reads.insert(id, waiter);
+                    (messages, Vec::new())
+                }
+                result => {
+                    let error = result.err().unwrap_or_else(|| {
+                        KvError::Decode("reply does not match request".to_string())
+                    });
+                    (Vec::new(), vec![answer(waiter, Err(error))])
+                }
+            };
+        }
+
+        let answers = self
+            .total
+            .on_outcome(outcome)
+            .and_then(|gathered| {
+                let waiter = self.reads.remove(&gathered.id)?;
+                let result = gathered
+                    .result
+                    .map(|value| ResponseType::ReadOk(ReadOkBody { value }));
+                Some(answer(waiter, result))
+            })
+            .into_iter()
+            .collect();
+        (Vec::new(), answers)
+    }
+}
+
+// There is only ever one, so the size difference doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum Backend {
+    /// Each node keeps a [`GCounter`] and gossips it to the others.
+    Gossip(Gossip<GCounter>),
+    SeqKv(KvCounter),
+}
+
 struct GrowOnlyCounterNode {
-    msg_id: MessageID,
-    node_id: String,
-    counters: HashMap<String, u64>,
-    last_broadcast: Instant,
+    /// Shared with the seq-kv client, so replies to clients and requests to
+    /// the service never reuse an id.
+    ids: MessageIds,
+    cluster: Cluster,
+    backend: Backend,
+}
+
+impl GrowOnlyCounterNode {
+    fn send_gossip(
+        &self,
+        messages: Vec<(String, GossipMessage<GCounter>)>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        for (dst, kind) in messages {
+            let message = Message {
+                src: self.cluster.node_id().to_string(),
+                dst,
+                body: MessageBody {
+                    kind: MessageType::Gossip(kind),
+                    msg_id: None,
+                },
+            };
+
+            output
+                .send(&message)
+                .context("serializing gossip message")?;
+        }
+
+        Ok(())
+    }
+
+    fn send_kv(
+        &mut self,
+        (messages, answers): (Vec<Message<kv::MessageType<u64>>>, Vec<Answer>),
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        for message in messages {
+            output.send(&message).context("serializing kv request")?;
+        }
+
+        for answer in answers {
+            match answer.result {
+                Ok(kind) => {
+                    let reply = Response {
+                        src: self.cluster.node_id().to_string(),
+                        dst: answer.client,
+                        body: ResponseBody {
+                            kind,
+                            msg_id: Some(self.ids.next()),
+                            in_reply_to: answer.in_reply_to,
+                        },
+                    };
+                    output.send(&reply).context("serializing response")?;
+                }
+                Err(error) => {
+                    let reply = Message {
+                        src: self.cluster.node_id().to_string(),
+                        dst: answer.client,
+                        body: gossip_glomers::MessageBody {
+                            kind: ErrorMessageType::Error(ErrorBody {
+                                // A timed out add may still have counted.
+                                code: match error {
+                                    KvError::Timeout => ErrorBody::TIMEOUT,
+                                    _ => ErrorBody::TEMPORARILY_UNAVAILABLE,
+                                },
+                                text: error.to_string(),
+                                in_reply_to: answer.in_reply_to,
+                            }),
+                            msg_id: Some(self.ids.next()),
+                        },
+                    };
+                    output.send(&reply).context("serializing error")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Node<MessageType> for GrowOnlyCounterNode {
-    fn init(message: InitBody) -> Self {
+    const TICK_INTERVAL: Option<Duration> = Some(TICK_INTERVAL);
+    // `add` is not idempotent, so a retried one must not count twice.
+    const DEDUP_WINDOW: Option<usize> = Some(10_000);
+
+    type Config = BackendKind;
+
+    /// A rejected `add` fails definitely, so the client knows it did not
+    /// count. seq-kv traffic goes to a service, not a peer, so it is never
+    /// throttled.
+    fn limits() -> Limits {
+        Limits {
+            outbound: HashMap::from([
+                ("gossip_delta".to_string(), GOSSIP_RATE),
+                ("gossip_ack".to_string(), GOSSIP_RATE),
+            ]),
+            inbound_capacity: Some(INBOUND_CAPACITY),
+            shed: HashSet::from(["add".to_string(), "read".to_string()]),
+            ..Limits::default()
+        }
+    }
+
+    fn init(message: InitBody, backend: BackendKind) -> Self {
+        let cluster = Cluster::from(message);
+        let ids = MessageIds::default();
+        let backend = match backend {
+            BackendKind::Gossip => Backend::Gossip(Gossip::new(
+                &cluster,
+                cluster.peers().cloned().collect(),
+                GCounter::default(),
+                gossip::Config {
+                    interval: GOSSIP_INTERVAL,
+                    mode: gossip::Mode::Delta,
+                    // Every node is a peer, so nothing needs passing on.
+                    relay: false,
+                    ..gossip::Config::default()
+                },
+                Instant::now(),
+            )),
+            BackendKind::SeqKv => Backend::SeqKv(KvCounter::new(&cluster, ids.clone())),
+        };
+
         Self {
-            msg_id: 1,
-            node_id: message.node_id.clone(),
-            counters: message
-                .node_ids
-                .iter()
-                .map(|node_id| (node_id.clone(), 0))
-                .chain([(message.node_id.clone(), 0)])
-                .collect(),
-            last_broadcast: Instant::now(),
+            ids,
+            backend,
+            cluster,
         }
     }
 
@@ -72,87 +400,279 @@ impl Node<MessageType> for GrowOnlyCounterNode {
         message: Message<MessageType>,
         output: &mut Output,
     ) -> anyhow::Result<()> {
+        let gossip = match &mut self.backend {
+            Backend::Gossip(gossip) => gossip,
+            Backend::SeqKv(counter) => {
+                let request = match message.body.kind {
+                    MessageType::Add(body) => counter.add(
+                        self.cluster.node_id(),
+                        message.src,
+                        message.body.msg_id,
+                        body.delta,
+                    ),
+                    MessageType::Read => {
+                        counter.read(self.cluster.node_id(), message.src, message.body.msg_id)
+                    }
+                    MessageType::Gossip(_) => return Ok(()),
+                };
+                output.send(&request).context("serializing kv request")?;
+                return Ok(());
+            }
+        };
+
         match message.body.kind {
             MessageType::Add(body) => {
-                *self.counters.get_mut(&self.node_id).unwrap() += body.delta;
+                let node_id = self.cluster.node_id();
+                gossip.update(|counter| counter.increment(node_id, body.delta));
+
+                output
+                    .persist(&serde_json::to_vec(&gossip.state().get(node_id))?)
+                    .context("persisting counter")?;
 
                 let reply = Response {
-                    src: self.node_id.clone(),
+                    src: self.cluster.node_id().to_string(),
                     dst: message.src.clone(),
                     body: ResponseBody {
                         kind: ResponseType::AddOk,
-                        msg_id: Some(self.msg_id),
+                        msg_id: Some(self.ids.next()),
                         in_reply_to: message.body.msg_id,
                     },
                 };
 
-                reply
-                    .serialize(&mut *output)
-                    .context("serializing add_ok response")?;
-
-                self.msg_id += 1;
+                output.send(&reply).context("serializing add_ok response")?;
             }
             MessageType::Read => {
-                let total = self.counters.values().sum::<u64>();
+                let total = gossip.state().value();
 
                 let reply = Response {
-                    src: self.node_id.clone(),
+                    src: self.cluster.node_id().to_string(),
                     dst: message.src.clone(),
                     body: ResponseBody {
                         kind: ResponseType::ReadOk(ReadOkBody { value: total }),
-                        msg_id: Some(self.msg_id),
+                        msg_id: Some(self.ids.next()),
                         in_reply_to: message.body.msg_id,
                     },
                 };
 
-                reply
-                    .serialize(&mut *output)
+                output
+                    .send(&reply)
                     .context("serializing read_ok response")?;
-
-                self.msg_id += 1;
             }
-            MessageType::Broadcast(body) => {
-                body.values
-                    .iter()
-                    .filter(|(k, _)| **k != self.node_id)
-                    .for_each(|(node_id, incoming)| match self.counters.get_mut(node_id) {
-                        Some(current) => {
-                            if incoming > current {
-                                *current = *incoming;
-                            }
-                        }
-                        None => {
-                            self.counters.insert(node_id.clone(), *incoming);
-                        }
-                    });
+            MessageType::Gossip(kind) => {
+                let replies = gossip.on_message(&message.src, kind);
+                self.send_gossip(replies, output)?;
             }
         }
 
-        if self.last_broadcast.elapsed() >= BROADCAST_INTERVAL {
-            self.last_broadcast = Instant::now();
-
-            for dst in self.counters.keys().filter(|k| **k != self.node_id) {
-                let message = Message {
-                    src: self.node_id.clone(),
-                    dst: dst.clone(),
-                    body: MessageBody {
-                        kind: MessageType::Broadcast(BroadcastBody {
-                            values: self.counters.clone(),
-                        }),
-                        msg_id: None,
-                    },
-                };
+        Ok(())
+    }
+
+    fn on_service(&mut self, service: Service, output: &mut Output) -> anyhow::Result<()> {
+        let (Backend::SeqKv(counter), Service::KeyValue(response)) = (&mut self.backend, service)
+        else {
+            return Ok(());
+        };
+
+        let sends = counter.on_service(response, Instant::now());
+        self.send_kv(sends, output)
+    }
 
-                message
-                    .serialize(&mut *output)
-                    .context("serializing broadcast message")?;
+    fn on_tick(&mut self, output: &mut Output) -> anyhow::Result<()> {
+        let now = Instant::now();
+        match &mut self.backend {
+            Backend::Gossip(gossip) => {
+                let messages = gossip.tick(now);
+                self.send_gossip(messages, output)
+            }
+            Backend::SeqKv(counter) => {
+                let sends = counter.tick(now);
+                self.send_kv(sends, output)
             }
         }
+    }
+
+    // The seq-kv backend keeps nothing on the node, so only the gossip one
+    // persists anything.
+
+    fn replay(&mut self, record: &[u8]) -> anyhow::Result<()> {
+        let Backend::Gossip(gossip) = &mut self.backend else {
+            return Ok(());
+        };
+
+        let total = serde_json::from_slice(record)?;
+        gossip.state_mut().merge(GCounter::from_iter([(
+            self.cluster.node_id().to_string(),
+            total,
+        )]));
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let Backend::Gossip(gossip) = &self.backend else {
+            return Vec::new();
+        };
+
+        serde_json::to_vec(gossip.state()).expect("a map of integers always serializes")
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()> {
+        let Backend::Gossip(gossip) = &mut self.backend else {
+            return Ok(());
+        };
+
+        gossip.state_mut().merge(serde_json::from_slice(snapshot)?);
 
         Ok(())
     }
 }
 
 pub fn main() -> anyhow::Result<()> {
-    gossip_glomers::run::<GrowOnlyCounterNode, MessageType>()
+    let backend = BackendKind::from_args(std::env::args().skip(1))?;
+
+    gossip_glomers::run::<GrowOnlyCounterNode, MessageType>(backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gossip_glomers::{
+        codec::round_trip,
+        kv::{Memory, MemoryStore, SeqKv},
+    };
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Add(AddBody { delta: 5 }),
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Read,
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Message {
+            src: "n2".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Gossip(GossipMessage::Push(gossip::GossipBody {
+                    state: GCounter::from_iter([("n1".to_string(), 5), ("n2".to_string(), 3)]),
+                })),
+                msg_id: None,
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::AddOk,
+                msg_id: Some(1),
+                in_reply_to: Some(1),
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::ReadOk(ReadOkBody { value: 8 }),
+                msg_id: Some(1),
+                in_reply_to: Some(1),
+            },
+        });
+    }
+
+    #[test]
+    fn test_backend_from_args() {
+        let args = |args: &[&str]| BackendKind::from_args(args.iter().map(|a| a.to_string()));
+
+        assert_eq!(args(&[]).unwrap(), BackendKind::Gossip);
+        assert_eq!(args(&["--backend", "seq-kv"]).unwrap(), BackendKind::SeqKv);
+        assert!(args(&["--backend", "lin-kv"]).is_err());
+        assert!(args(&["--backend"]).is_err());
+    }
+
+    #[test]
+    fn test_limits_cover_gossip_and_client_requests() {
+        let limits = GrowOnlyCounterNode::limits();
+
+        for sent in ["gossip_delta", "gossip_ack"] {
+            assert!(limits.outbound.contains_key(sent), "{sent} is unlimited");
+        }
+        assert_eq!(limits.outbound_default, None);
+
+        assert!(limits.inbound_capacity.is_some());
+        assert_eq!(
+            limits.shed,
+            HashSet::from(["add".to_string(), "read".to_string()])
+        );
+    }
+
+    fn cluster(node_id: &str) -> Cluster {
+        Cluster::from(InitBody {
+            node_id: node_id.to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        })
+    }
+
+    /// Hands every reply back until the counter stops asking for more.
+    fn settle(counter: &mut KvCounter<Memory<SeqKv>>, now: Instant) -> Vec<Answer> {
+        let mut answers = Vec::new();
+        loop {
+            let replies = counter.client.replies();
+            if replies.is_empty() {
+                return answers;
+            }
+
+            for response in replies {
+                answers.extend(counter.on_service(response, now).1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seq_kv_backend() {
+        let store = MemoryStore::default();
+        let now = Instant::now();
+        let mut n1 = KvCounter::with_client(&cluster("n1"), Memory::new("n1".to_string(), &store));
+        let mut n2 = KvCounter::with_client(
+            &cluster("n2"),
+            Memory::new("n2".to_string(), &store).with_staleness(),
+        );
+
+        n1.client.fail_cas(1);
+        n1.add("n1", "c1".to_string(), Some(1), 5);
+        assert!(settle(&mut n1, now).is_empty());
+
+        n1.tick(now + Duration::from_secs(1));
+        assert_eq!(
+            settle(&mut n1, now),
+            vec![Answer {
+                client: "c1".to_string(),
+                in_reply_to: Some(1),
+                result: Ok(ResponseType::AddOk),
+            }]
+        );
+        n1.add("n1", "c1".to_string(), Some(2), 2);
+        settle(&mut n1, now);
+        assert_eq!(store.get("counter-n1"), Some(serde_json::json!(7)));
+
+        // n2's view predates both adds, but a read syncs before it sums.
+        n2.read("n2", "c2".to_string(), Some(1));
+        assert_eq!(
+            settle(&mut n2, now),
+            vec![Answer {
+                client: "c2".to_string(),
+                in_reply_to: Some(1),
+                result: Ok(ResponseType::ReadOk(ReadOkBody { value: 7 })),
+            }]
+        );
+    }
 }
@@ -0,0 +1,399 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gossip_glomers::{
+    ErrorBody, ErrorMessageType, InitBody, Message, MessageID, Node, Output,
+    cluster::Cluster,
+    election::{Term, Timeouts},
+    raft::{LogIndex, Raft, RaftMessage, StateMachine, StepFor},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct ReadBody {
+    key: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct WriteBody {
+    key: u64,
+    value: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct CasBody {
+    key: u64,
+    from: u64,
+    to: u64,
+}
+
+/// A client request. Each one is replicated through the Raft log, reads
+/// included, so every reply reflects a single order of operations.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Operation {
+    Read(ReadBody),
+    Write(WriteBody),
+    Cas(CasBody),
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct ReadOkBody {
+    value: u64,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseType {
+    ReadOk(ReadOkBody),
+    WriteOk,
+    CasOk,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+enum MessageType {
+    Operation(Operation),
+    Raft(RaftMessage<Operation>),
+}
+
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+type MessageBody = gossip_glomers::MessageBody<MessageType>;
+
+type ResponseBody = gossip_glomers::ResponseBody<ResponseType>;
+
+type Response = gossip_glomers::Response<ResponseType>;
+
+/// The replicated map from keys to values.
+#[derive(Default)]
+struct Registers(HashMap<u64, u64>);
+
+impl StateMachine for Registers {
+    type Command = Operation;
+    type Output = Result<ResponseType, ErrorBody>;
+
+    fn apply(&mut self, operation: &Operation) -> Self::Output {
+        let missing = |key| ErrorBody {
+            code: ErrorBody::KEY_DOES_NOT_EXIST,
+            text: format!("key {key} does not exist"),
+            in_reply_to: None,
+        };
+
+        match *operation {
+            Operation::Read(ReadBody { key }) => match self.0.get(&key) {
+                Some(&value) => Ok(ResponseType::ReadOk(ReadOkBody { value })),
+                None => Err(missing(key)),
+            },
+            Operation::Write(WriteBody { key, value }) => {
+                self.0.insert(key, value);
+                Ok(ResponseType::WriteOk)
+            }
+            Operation::Cas(CasBody { key, from, to }) => match self.0.get_mut(&key) {
+                Some(value) if *value == from => {
+                    *value = to;
+                    Ok(ResponseType::CasOk)
+                }
+                Some(value) => Err(ErrorBody {
+                    code: ErrorBody::PRECONDITION_FAILED,
+                    text: format!("current value {value} is not {from}"),
+                    in_reply_to: None,
+                }),
+                None => Err(missing(key)),
+            },
+        }
+    }
+}
+
+/// For a client whose entry was overwritten before it committed.
+fn lost_leadership() -> ErrorBody {
+    ErrorBody {
+        code: ErrorBody::TEMPORARILY_UNAVAILABLE,
+        text: "lost leadership before the operation committed".to_string(),
+        in_reply_to: None,
+    }
+}
+
+/// A client waiting on the entry this node proposed at some index.
+struct Pending {
+    /// The term it was proposed in. If a different entry is applied at its
+    /// index, the proposal was overwritten and never took effect.
+    term: Term,
+    client: String,
+    in_reply_to: Option<MessageID>,
+}
+
+struct LinKvNode {
+    msg_id: MessageID,
+    node_id: String,
+    raft: Raft<Registers>,
+    pending: HashMap<LogIndex, Pending>,
+}
+
+impl LinKvNode {
+    fn reply(
+        &mut self,
+        client: String,
+        in_reply_to: Option<MessageID>,
+        result: Result<ResponseType, ErrorBody>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        match result {
+            Ok(kind) => {
+                let reply = Response {
+                    src: self.node_id.clone(),
+                    dst: client,
+                    body: ResponseBody {
+                        kind,
+                        msg_id: Some(self.msg_id),
+                        in_reply_to,
+                    },
+                };
+                output.send(&reply).context("serializing response")?;
+            }
+            Err(error) => {
+                let reply = Message {
+                    src: self.node_id.clone(),
+                    dst: client,
+                    body: gossip_glomers::MessageBody {
+                        kind: ErrorMessageType::Error(ErrorBody {
+                            in_reply_to,
+                            ..error
+                        }),
+                        msg_id: Some(self.msg_id),
+                    },
+                };
+                output.send(&reply).context("serializing error")?;
+            }
+        }
+        self.msg_id += 1;
+
+        Ok(())
+    }
+
+    /// Persists, sends and answers whatever a Raft step produced. The
+    /// records reach the log before the runtime writes any of the messages.
+    fn drive(&mut self, step: StepFor<Registers>, output: &mut Output) -> anyhow::Result<()> {
+        for record in step.persist {
+            output
+                .persist(&serde_json::to_vec(&record)?)
+                .context("persisting raft state")?;
+        }
+
+        for (peer, kind) in step.messages {
+            let message = Message {
+                src: self.node_id.clone(),
+                dst: peer,
+                body: MessageBody {
+                    kind: MessageType::Raft(kind),
+                    msg_id: None,
+                },
+            };
+            output.send(&message).context("serializing raft message")?;
+        }
+
+        for applied in step.applied {
+            let Some(pending) = self.pending.remove(&applied.index) else {
+                continue;
+            };
+
+            let result = if applied.term == pending.term {
+                applied.output
+            } else {
+                Err(lost_leadership())
+            };
+            self.reply(pending.client, pending.in_reply_to, result, output)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Node<MessageType> for LinKvNode {
+    const TICK_INTERVAL: Option<Duration> = Some(TICK_INTERVAL);
+
+    type Config = ();
+
+    fn init(message: InitBody, _config: ()) -> Self {
+        let node_id = message.node_id.clone();
+
+        Self {
+            msg_id: 1,
+            node_id,
+            raft: Raft::new(
+                Cluster::from(message),
+                Timeouts::default(),
+                Registers::default(),
+                Instant::now(),
+            ),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn on_message(
+        &mut self,
+        message: Message<MessageType>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        match message.body.kind {
+            MessageType::Operation(operation) => match self.raft.propose(operation) {
+                Ok((index, step)) => {
+                    let displaced = self.pending.insert(
+                        index,
+                        Pending {
+                            term: self.raft.term(),
+                            client: message.src,
+                            in_reply_to: message.body.msg_id,
+                        },
+                    );
+                    // An earlier proposal at this index was truncated before
+                    // it committed, and its client would otherwise never hear.
+                    if let Some(displaced) = displaced {
+                        self.reply(
+                            displaced.client,
+                            displaced.in_reply_to,
+                            Err(lost_leadership()),
+                            output,
+                        )?;
+                    }
+                    self.drive(step, output)
+                }
+                Err(not_leader) => {
+                    let text = match not_leader.leader {
+                        Some(leader) => format!("not the leader; try {leader}"),
+                        None => "no leader elected yet".to_string(),
+                    };
+                    let error = ErrorBody {
+                        code: ErrorBody::TEMPORARILY_UNAVAILABLE,
+                        text,
+                        in_reply_to: None,
+                    };
+                    self.reply(message.src, message.body.msg_id, Err(error), output)
+                }
+            },
+            MessageType::Raft(kind) => {
+                let step = self.raft.on_message(&message.src, kind, Instant::now());
+                self.drive(step, output)
+            }
+        }
+    }
+
+    fn on_tick(&mut self, output: &mut Output) -> anyhow::Result<()> {
+        let step = self.raft.tick(Instant::now());
+        self.drive(step, output)
+    }
+
+    fn replay(&mut self, record: &[u8]) -> anyhow::Result<()> {
+        self.raft.replay(serde_json::from_slice(record)?);
+
+        Ok(())
+    }
+}
+
+pub fn main() -> anyhow::Result<()> {
+    gossip_glomers::run::<LinKvNode, MessageType>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gossip_glomers::{
+        codec::round_trip,
+        election::ElectionMessage,
+        raft::{AppendEntriesBody, LogEntry},
+    };
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Operation(Operation::Cas(CasBody {
+                    key: 1,
+                    from: 2,
+                    to: 3,
+                })),
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Message {
+            src: "n2".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Raft(RaftMessage::AppendEntries(AppendEntriesBody {
+                    term: 2,
+                    prev_log_index: 1,
+                    prev_log_term: 1,
+                    entries: vec![LogEntry {
+                        term: 2,
+                        command: Some(Operation::Write(WriteBody { key: 1, value: 2 })),
+                    }],
+                    leader_commit: 1,
+                })),
+                msg_id: None,
+            },
+        });
+        round_trip(Message {
+            src: "n2".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Raft(RaftMessage::Election(ElectionMessage::Vote(
+                    gossip_glomers::election::VoteBody {
+                        term: 2,
+                        granted: true,
+                    },
+                ))),
+                msg_id: None,
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::ReadOk(ReadOkBody { value: 3 }),
+                msg_id: Some(1),
+                in_reply_to: Some(1),
+            },
+        });
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut registers = Registers::default();
+        let code = |result: Result<ResponseType, ErrorBody>| result.unwrap_err().code;
+
+        assert_eq!(
+            code(registers.apply(&Operation::Read(ReadBody { key: 1 }))),
+            ErrorBody::KEY_DOES_NOT_EXIST
+        );
+        assert_eq!(
+            registers.apply(&Operation::Write(WriteBody { key: 1, value: 2 })),
+            Ok(ResponseType::WriteOk)
+        );
+        assert_eq!(
+            code(registers.apply(&Operation::Cas(CasBody {
+                key: 1,
+                from: 3,
+                to: 4,
+            }))),
+            ErrorBody::PRECONDITION_FAILED
+        );
+        assert_eq!(
+            registers.apply(&Operation::Cas(CasBody {
+                key: 1,
+                from: 2,
+                to: 4,
+            })),
+            Ok(ResponseType::CasOk)
+        );
+        assert_eq!(
+            registers.apply(&Operation::Read(ReadBody { key: 1 })),
+            Ok(ResponseType::ReadOk(ReadOkBody { value: 4 }))
+        );
+    }
+}
@@ -3,12 +3,12 @@ use gossip_glomers::{InitBody, Message, MessageID, Node, Output};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 struct GenerateOkBody {
     id: Uuid,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ResponseType {
     GenerateOk(GenerateOkBody),
@@ -33,14 +33,20 @@ struct UniqueIDNode {
 }
 
 impl Node<MessageType> for UniqueIDNode {
-    fn init(message: InitBody) -> Self {
+    type Config = ();
+
+    fn init(message: InitBody, _config: ()) -> Self {
         Self {
             msg_id: 1,
             node_id: message.node_id,
         }
     }
 
-    fn on_message(&mut self, message: Message<MessageType>, output: &mut Output) -> anyhow::Result<()> {
+    fn on_message(
+        &mut self,
+        message: Message<MessageType>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
         match message.body.kind {
             MessageType::Generate => {
                 let reply = Response {
@@ -53,8 +59,8 @@ impl Node<MessageType> for UniqueIDNode {
                     },
                 };
 
-                reply
-                    .serialize(output)
+                output
+                    .send(&reply)
                     .context("serializing generate_ok response")?;
                 self.msg_id += 1;
 
@@ -65,7 +71,34 @@ impl Node<MessageType> for UniqueIDNode {
 }
 
 pub fn main() -> anyhow::Result<()> {
-    gossip_glomers::run::<UniqueIDNode, MessageType>()?;
+    gossip_glomers::run::<UniqueIDNode, MessageType>(())?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gossip_glomers::{MessageBody, codec::round_trip};
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Generate,
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::GenerateOk(GenerateOkBody { id: Uuid::now_v7() }),
+                msg_id: Some(1),
+                in_reply_to: Some(1),
+            },
+        });
+    }
+}
@@ -0,0 +1,291 @@
+use crate::InitBody;
+
+// Points each node gets on the hash ring, to even out how many keys land on
+// each of a handful of nodes.
+const VIRTUAL_NODES: usize = 64;
+
+/// Shapes Maelstrom (and `broadcast`'s `topology` message) lay nodes out in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Topology {
+    /// Every node talks to every other node.
+    Total,
+    /// Nodes in index order, each talking to the one before and after.
+    Line,
+    /// A line with its two ends joined.
+    Ring,
+    /// Nodes laid out row by row in a square grid, talking to the nodes
+    /// above, below, left and right of them.
+    Grid,
+    /// A tree with the given number of children per node, rooted at index 0.
+    Tree(usize),
+}
+
+/// The fixed set of nodes handed out in the `init` message, seen from one of
+/// them.
+///
+/// Nodes are ordered by id, so every member agrees on indices, the leader and
+/// who owns a key without having to talk to each other.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cluster {
+    node_id: String,
+    node_ids: Vec<String>,
+    index: usize,
+    ring: Vec<(u64, usize)>,
+}
+
+impl From<InitBody> for Cluster {
+    fn from(init: InitBody) -> Self {
+        let mut node_ids = init.node_ids;
+        if !node_ids.contains(&init.node_id) {
+            node_ids.push(init.node_id.clone());
+        }
+        node_ids.sort();
+        node_ids.dedup();
+
+        let index = node_ids.binary_search(&init.node_id).unwrap();
+
+        let mut ring: Vec<(u64, usize)> = node_ids
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node_id)| {
+                (0..VIRTUAL_NODES).map(move |v| (hash(format!("{node_id}#{v}").as_bytes()), i))
+            })
+            .collect();
+        ring.sort();
+
+        Self {
+            node_id: init.node_id,
+            node_ids,
+            index,
+            ring,
+        }
+    }
+}
+
+impl Cluster {
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Every node in the cluster, including this one, in index order.
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    /// Every node in the cluster except this one.
+    pub fn peers(&self) -> impl Iterator<Item = &String> {
+        self.node_ids.iter().filter(|&n| n != &self.node_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.node_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.node_ids.is_empty()
+    }
+
+    /// This node's position in [`Cluster::node_ids`].
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn index_of(&self, node_id: &str) -> Option<usize> {
+        self.node_ids.iter().position(|n| n == node_id)
+    }
+
+    /// The node every member agrees should coordinate cluster-wide work.
+    pub fn leader(&self) -> &str {
+        &self.node_ids[0]
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.index == 0
+    }
+
+    /// The node responsible for `key` on the consistent hash ring.
+    pub fn owner(&self, key: &str) -> &str {
+        self.replicas(key, 1)[0]
+    }
+
+    /// The first `n` distinct nodes clockwise from `key` on the hash ring,
+    /// starting with its owner.
+    pub fn replicas(&self, key: &str, n: usize) -> Vec<&str> {
+        let n = n.min(self.len());
+        let start = self
+            .ring
+            .partition_point(|&(point, _)| point < hash(key.as_bytes()));
+
+        let mut replicas: Vec<&str> = Vec::with_capacity(n);
+        for &(_, i) in self.ring[start..].iter().chain(&self.ring[..start]) {
+            if replicas.len() == n {
+                break;
+            }
+            if !replicas.contains(&self.node_ids[i].as_str()) {
+                replicas.push(&self.node_ids[i]);
+            }
+        }
+
+        replicas
+    }
+
+    /// This node's neighbours when the cluster is arranged as `topology`.
+    pub fn neighbours(&self, topology: Topology) -> Vec<String> {
+        let n = self.len();
+        let i = self.index;
+
+        let indices: Vec<usize> = match topology {
+            Topology::Total => (0..n).filter(|&j| j != i).collect(),
+            Topology::Line => [i.checked_sub(1), Some(i + 1).filter(|&j| j < n)]
+                .into_iter()
+                .flatten()
+                .collect(),
+            Topology::Ring => {
+                let mut ring = vec![(i + n - 1) % n, (i + 1) % n];
+                ring.sort();
+                ring.dedup();
+                ring.retain(|&j| j != i);
+                ring
+            }
+            Topology::Grid => {
+                let width = (1..).find(|w| w * w >= n).unwrap();
+                let (row, col) = (i / width, i % width);
+
+                [
+                    row.checked_sub(1).map(|r| r * width + col),
+                    col.checked_sub(1).map(|c| row * width + c),
+                    Some(row * width + col + 1).filter(|_| col + 1 < width),
+                    Some((row + 1) * width + col),
+                ]
+                .into_iter()
+                .flatten()
+                .filter(|&j| j < n)
+                .collect()
+            }
+            Topology::Tree(children) => {
+                let children = children.max(1);
+
+                i.checked_sub(1)
+                    .map(|p| p / children)
+                    .into_iter()
+                    .chain((i * children + 1..=i * children + children).filter(|&j| j < n))
+                    .collect()
+            }
+        };
+
+        indices
+            .into_iter()
+            .map(|j| self.node_ids[j].clone())
+            .collect()
+    }
+}
+
+/// 64-bit FNV-1a with a murmur3 finalizer to spread short, similar keys
+/// across the ring. Unlike the std hasher it is the same in every build, so
+/// all nodes place keys identically.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    let mut h = bytes.iter().fold(0xcbf29ce484222325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(node_id: &str, n: usize) -> Cluster {
+        Cluster::from(InitBody {
+            node_id: node_id.to_string(),
+            node_ids: (1..=n).rev().map(|i| format!("n{i}")).collect(),
+        })
+    }
+
+    #[test]
+    fn test_membership() {
+        let cluster = cluster("n2", 3);
+
+        assert_eq!(cluster.node_id(), "n2");
+        assert_eq!(cluster.node_ids(), ["n1", "n2", "n3"]);
+        assert_eq!(cluster.peers().collect::<Vec<_>>(), ["n1", "n3"]);
+        assert_eq!(cluster.index(), 1);
+        assert_eq!(cluster.index_of("n3"), Some(2));
+        assert_eq!(cluster.leader(), "n1");
+        assert!(!cluster.is_leader());
+    }
+
+    #[test]
+    fn test_every_node_agrees_on_owners() {
+        let a = cluster("n1", 5);
+        let b = cluster("n4", 5);
+
+        for key in ["a", "b", "counter", "log-17"] {
+            assert_eq!(a.owner(key), b.owner(key));
+            assert_eq!(a.replicas(key, 3), b.replicas(key, 3));
+        }
+    }
+
+    #[test]
+    fn test_replicas_are_distinct() {
+        let cluster = cluster("n1", 3);
+
+        let mut replicas = cluster.replicas("key", 5);
+        assert_eq!(replicas[0], cluster.owner("key"));
+
+        replicas.sort();
+        assert_eq!(replicas, ["n1", "n2", "n3"]);
+    }
+
+    #[test]
+    fn test_ring_spreads_keys() {
+        let cluster = cluster("n1", 5);
+
+        for node_id in cluster.node_ids() {
+            let owned = (0..1000)
+                .filter(|k| cluster.owner(&k.to_string()) == node_id)
+                .count();
+            assert!(owned > 100, "{node_id} owns only {owned} of 1000 keys");
+        }
+    }
+
+    #[test]
+    fn test_neighbours() {
+        assert_eq!(cluster("n1", 3).neighbours(Topology::Total), ["n2", "n3"]);
+
+        assert_eq!(cluster("n1", 3).neighbours(Topology::Line), ["n2"]);
+        assert_eq!(cluster("n2", 3).neighbours(Topology::Line), ["n1", "n3"]);
+
+        assert_eq!(cluster("n1", 4).neighbours(Topology::Ring), ["n2", "n4"]);
+        assert_eq!(cluster("n1", 2).neighbours(Topology::Ring), ["n2"]);
+
+        // n1 n2 n3
+        // n4 n5
+        assert_eq!(
+            cluster("n2", 5).neighbours(Topology::Grid),
+            ["n1", "n3", "n5"]
+        );
+        assert_eq!(cluster("n4", 5).neighbours(Topology::Grid), ["n1", "n5"]);
+
+        assert_eq!(cluster("n1", 5).neighbours(Topology::Tree(2)), ["n2", "n3"]);
+        assert_eq!(
+            cluster("n2", 5).neighbours(Topology::Tree(2)),
+            ["n1", "n4", "n5"]
+        );
+        assert_eq!(cluster("n5", 5).neighbours(Topology::Tree(2)), ["n2"]);
+    }
+
+    #[test]
+    fn test_single_node() {
+        let cluster = cluster("n1", 1);
+
+        assert!(cluster.is_leader());
+        assert_eq!(cluster.owner("key"), "n1");
+        assert!(cluster.neighbours(Topology::Ring).is_empty());
+        assert!(cluster.neighbours(Topology::Grid).is_empty());
+    }
+}
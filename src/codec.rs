@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::{BufRead, Write},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// The codec to speak to other nodes: `json-lines` (the default) or
+/// `msgpack`.
+pub const PEER_CODEC_VAR: &str = "GOSSIP_GLOMERS_PEER_CODEC";
+
+/// Encodes and decodes whole messages on a byte stream.
+///
+/// Each encoded value is self-delimiting, so a reader can pull messages off a
+/// stream one at a time without any extra framing.
+pub trait Codec {
+    fn encode<T: Serialize, W: Write>(&self, value: &T, w: &mut W) -> anyhow::Result<()>;
+
+    /// Returns `None` once the stream is exhausted.
+    fn decode<T: DeserializeOwned, R: BufRead>(&self, r: &mut R) -> anyhow::Result<Option<T>>;
+}
+
+/// One JSON document per line, as spoken by Maelstrom on stdin/stdout.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JsonLines;
+
+impl Codec for JsonLines {
+    fn encode<T: Serialize, W: Write>(&self, value: &T, w: &mut W) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut *w, value).context("encoding JSON")?;
+        w.write_all(b"\n").context("terminating JSON line")
+    }
+
+    fn decode<T: DeserializeOwned, R: BufRead>(&self, r: &mut R) -> anyhow::Result<Option<T>> {
+        let mut line = String::new();
+        if r.read_line(&mut line).context("reading JSON line")? == 0 {
+            return Ok(None);
+        }
+
+        serde_json::from_str(&line)
+            .map(Some)
+            .context("decoding JSON")
+    }
+}
+
+/// MessagePack with structs encoded as maps, so that tagged and flattened
+/// bodies survive the trip the same way they do in JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode<T: Serialize, W: Write>(&self, value: &T, w: &mut W) -> anyhow::Result<()> {
+        rmp_serde::encode::write_named(w, value).context("encoding MessagePack")
+    }
+
+    fn decode<T: DeserializeOwned, R: BufRead>(&self, r: &mut R) -> anyhow::Result<Option<T>> {
+        if r.fill_buf().context("reading MessagePack")?.is_empty() {
+            return Ok(None);
+        }
+
+        rmp_serde::from_read(r)
+            .map(Some)
+            .context("decoding MessagePack")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WireCodec {
+    #[default]
+    JsonLines,
+    MessagePack,
+}
+
+impl Codec for WireCodec {
+    fn encode<T: Serialize, W: Write>(&self, value: &T, w: &mut W) -> anyhow::Result<()> {
+        match self {
+            WireCodec::JsonLines => JsonLines.encode(value, w),
+            WireCodec::MessagePack => MessagePack.encode(value, w),
+        }
+    }
+
+    fn decode<T: DeserializeOwned, R: BufRead>(&self, r: &mut R) -> anyhow::Result<Option<T>> {
+        match self {
+            WireCodec::JsonLines => JsonLines.decode(r),
+            WireCodec::MessagePack => MessagePack.decode(r),
+        }
+    }
+}
+
+impl FromStr for WireCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json-lines" => Ok(WireCodec::JsonLines),
+            "msgpack" => Ok(WireCodec::MessagePack),
+            _ => anyhow::bail!("unknown codec {s:?}"),
+        }
+    }
+}
+
+/// Chooses the codec for each link.
+///
+/// Only other nodes can be given a codec. Maelstrom, its clients and its
+/// services only speak JSON lines, so every other destination gets that.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Links {
+    codecs: HashMap<String, WireCodec>,
+}
+
+impl Links {
+    /// Speaks `codec` to every one of `peers`.
+    pub fn new(peers: impl IntoIterator<Item = String>, codec: WireCodec) -> Self {
+        Self {
+            codecs: peers.into_iter().map(|peer| (peer, codec)).collect(),
+        }
+    }
+
+    /// Speaks the codec named by [`PEER_CODEC_VAR`] to every one of `peers`.
+    pub fn from_env(peers: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let codec = match env::var(PEER_CODEC_VAR) {
+            Ok(codec) => codec.parse()?,
+            Err(env::VarError::NotPresent) => WireCodec::default(),
+            Err(e) => return Err(e).context(PEER_CODEC_VAR),
+        };
+
+        Ok(Self::new(peers, codec))
+    }
+
+    pub fn set(&mut self, peer: &str, codec: WireCodec) {
+        self.codecs.insert(peer.to_string(), codec);
+    }
+
+    pub fn codec(&self, dst: &str) -> WireCodec {
+        self.codecs.get(dst).copied().unwrap_or_default()
+    }
+
+    /// Whether every link speaks JSON lines, so nothing needs looking up.
+    pub(crate) fn all_json_lines(&self) -> bool {
+        self.codecs
+            .values()
+            .all(|&codec| codec == WireCodec::JsonLines)
+    }
+}
+
+/// Asserts that `value` survives an encode/decode trip through every wire
+/// codec, twice in a row on the same stream.
+///
+/// The node binaries check their own message types with this too, through
+/// the `test-support` feature.
+#[cfg(any(test, feature = "test-support"))]
+pub fn round_trip<T>(value: T)
+where
+    T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+    for codec in [WireCodec::JsonLines, WireCodec::MessagePack] {
+        let mut buf = Vec::new();
+        codec.encode(&value, &mut buf).unwrap();
+        codec.encode(&value, &mut buf).unwrap();
+
+        let mut r = buf.as_slice();
+        assert_eq!(codec.decode::<T, _>(&mut r).unwrap().as_ref(), Some(&value));
+        assert_eq!(codec.decode::<T, _>(&mut r).unwrap().as_ref(), Some(&value));
+        assert_eq!(codec.decode::<T, _>(&mut r).unwrap(), None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ErrorBody, ErrorMessageType, InitBody, Message, MessageBody, MessageIds, MessageType,
+        Response, ResponseBody, ResponseType, kv,
+    };
+
+    #[test]
+    fn test_json_lines_terminates_every_value() {
+        let mut buf = Vec::new();
+        JsonLines.encode(&1, &mut buf).unwrap();
+        JsonLines.encode(&[1, 2], &mut buf).unwrap();
+        JsonLines.encode(&"n1", &mut buf).unwrap();
+
+        assert_eq!(buf, b"1\n[1,2]\n\"n1\"\n");
+    }
+
+    #[test]
+    fn test_links_default_to_json_lines() {
+        let mut links = Links::new(["n2".to_string(), "n3".to_string()], WireCodec::MessagePack);
+        links.set("n3", WireCodec::JsonLines);
+
+        assert_eq!(links.codec("n2"), WireCodec::MessagePack);
+        assert_eq!(links.codec("n3"), WireCodec::JsonLines);
+        assert_eq!(links.codec("c1"), WireCodec::JsonLines);
+        assert_eq!(links.codec("seq-kv"), WireCodec::JsonLines);
+        assert!(!links.all_json_lines());
+        assert!(Links::default().all_json_lines());
+
+        assert_eq!(
+            "msgpack".parse::<WireCodec>().unwrap(),
+            WireCodec::MessagePack
+        );
+        assert!("cbor".parse::<WireCodec>().is_err());
+    }
+
+    #[test]
+    fn test_round_trip_init() {
+        round_trip(Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: MessageType::Init(InitBody {
+                    node_id: "n1".to_string(),
+                    node_ids: vec!["n1".to_string(), "n2".to_string()],
+                }),
+                msg_id: Some(1),
+            },
+        });
+        round_trip(Response {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: ResponseBody {
+                kind: ResponseType::InitOk,
+                msg_id: None,
+                in_reply_to: Some(1),
+            },
+        });
+    }
+
+    #[test]
+    fn test_round_trip_error() {
+        round_trip(Message {
+            src: "seq-kv".to_string(),
+            dst: "n1".to_string(),
+            body: MessageBody {
+                kind: ErrorMessageType::Error(ErrorBody {
+                    code: 20,
+                    text: "key does not exist".to_string(),
+                    in_reply_to: Some(3),
+                }),
+                msg_id: None,
+            },
+        });
+    }
+
+    #[test]
+    fn test_round_trip_kv() {
+        let mut client = kv::Sequential::new("n1".to_string(), MessageIds::default());
+
+        round_trip(client.read::<u64>("counter"));
+        round_trip(client.write("counter", 5u64));
+        round_trip(client.compare_and_swap("counter", 1u64, 2, true));
+
+        for kind in [
+            kv::ResponseType::ReadOk(kv::ReadOkBody {
+                value: Some(serde_json::json!(42)),
+            }),
+            kv::ResponseType::WriteOk,
+            kv::ResponseType::CompareAndSwapOk,
+            kv::ResponseType::Error(kv::ErrorBody {
+                code: 22,
+                text: "current value 3 is not 1".to_string(),
+            }),
+        ] {
+            round_trip(Response {
+                src: "seq-kv".to_string(),
+                dst: "n1".to_string(),
+                body: ResponseBody {
+                    kind,
+                    msg_id: Some(7),
+                    in_reply_to: Some(3),
+                },
+            });
+        }
+    }
+}
@@ -0,0 +1,660 @@
+//! State-based CRDTs: replicated values that every replica can change
+//! locally and that converge once replicas have merged each other's states.
+//!
+//! Every mutation returns a delta: a small state holding just that change,
+//! which has the same effect as the full state when merged elsewhere. Nodes
+//! can ship deltas instead of whole states, see [`crate::gossip::Mode::Delta`].
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// State that converges when replicas exchange copies: merging must be
+/// commutative, associative and idempotent, so it does not matter in which
+/// order, or how often, copies arrive.
+pub trait Merge: Sized {
+    /// Merges `other` in and returns the part of it that was new here, which
+    /// has the same effect as `other` when merged into this replica's old
+    /// state. `None` means nothing changed.
+    fn merge(&mut self, other: Self) -> Option<Self>;
+}
+
+/// A counter that only goes up, kept as one running total per node.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct GCounter(HashMap<String, u64>);
+
+impl GCounter {
+    pub fn increment(&mut self, node_id: &str, by: u64) -> Self {
+        let total = self.0.entry(node_id.to_string()).or_default();
+        *total += by;
+
+        Self(HashMap::from([(node_id.to_string(), *total)]))
+    }
+
+    /// How much `node_id` has added.
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.0.get(node_id).copied().unwrap_or_default()
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+impl FromIterator<(String, u64)> for GCounter {
+    fn from_iter<I: IntoIterator<Item = (String, u64)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Merge for GCounter {
+    fn merge(&mut self, other: Self) -> Option<Self> {
+        let mut new = HashMap::new();
+        for (node_id, incoming) in other.0 {
+            // A node first seen at zero is still new, or the part returned
+            // would not add up to what changed.
+            let current = self.0.get(&node_id);
+            if current.is_none_or(|&current| incoming > current) {
+                self.0.insert(node_id.clone(), incoming);
+                new.insert(node_id, incoming);
+            }
+        }
+
+        (!new.is_empty()).then_some(Self(new))
+    }
+}
+
+/// A counter that can go up and down, as a pair of [`GCounter`]s.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn increment(&mut self, node_id: &str, by: u64) -> Self {
+        Self {
+            increments: self.increments.increment(node_id, by),
+            decrements: GCounter::default(),
+        }
+    }
+
+    pub fn decrement(&mut self, node_id: &str, by: u64) -> Self {
+        Self {
+            increments: GCounter::default(),
+            decrements: self.decrements.increment(node_id, by),
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Merge for PnCounter {
+    fn merge(&mut self, other: Self) -> Option<Self> {
+        match (
+            self.increments.merge(other.increments),
+            self.decrements.merge(other.decrements),
+        ) {
+            (None, None) => None,
+            (increments, decrements) => Some(Self {
+                increments: increments.unwrap_or_default(),
+                decrements: decrements.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+/// A set that can only grow.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(transparent, bound(deserialize = "T: Eq + Hash + Deserialize<'de>"))]
+pub struct GSet<T: Eq + Hash>(HashSet<T>);
+
+impl<T: Eq + Hash> Default for GSet<T> {
+    fn default() -> Self {
+        Self(HashSet::new())
+    }
+}
+
+impl<T: Eq + Hash + Clone> GSet<T> {
+    /// Returns `None`, and changes nothing, if `value` was already there.
+    pub fn insert(&mut self, value: T) -> Option<Self> {
+        self.0
+            .insert(value.clone())
+            .then(|| Self(HashSet::from([value])))
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.0.contains(value)
+    }
+
+    pub fn elements(&self) -> &HashSet<T> {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T: Eq + Hash> From<HashSet<T>> for GSet<T> {
+    fn from(set: HashSet<T>) -> Self {
+        Self(set)
+    }
+}
+
+impl<T: Eq + Hash + Clone> Merge for GSet<T> {
+    fn merge(&mut self, other: Self) -> Option<Self> {
+        let new: HashSet<T> = other
+            .0
+            .into_iter()
+            .filter(|value| self.0.insert(value.clone()))
+            .collect();
+
+        (!new.is_empty()).then_some(Self(new))
+    }
+}
+
+/// A set where removal is permanent: once removed, an element can never be
+/// added back.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(bound(deserialize = "T: Eq + Hash + Deserialize<'de>"))]
+pub struct TwoPhaseSet<T: Eq + Hash> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Eq + Hash> Default for TwoPhaseSet<T> {
+    fn default() -> Self {
+        Self {
+            added: GSet::default(),
+            removed: GSet::default(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> TwoPhaseSet<T> {
+    pub fn insert(&mut self, value: T) -> Self {
+        self.added.insert(value.clone());
+
+        Self {
+            added: GSet::from(HashSet::from([value])),
+            removed: GSet::default(),
+        }
+    }
+
+    /// Removes `value` for good. Returns `None`, and does nothing, if it was
+    /// not in the set.
+    pub fn remove(&mut self, value: &T) -> Option<Self> {
+        if !self.contains(value) {
+            return None;
+        }
+        self.removed.insert(value.clone());
+
+        Some(Self {
+            added: GSet::default(),
+            removed: GSet::from(HashSet::from([value.clone()])),
+        })
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.added.contains(value) && !self.removed.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.added
+            .elements()
+            .iter()
+            .filter(|v| !self.removed.contains(v))
+    }
+}
+
+impl<T: Eq + Hash + Clone> Merge for TwoPhaseSet<T> {
+    fn merge(&mut self, other: Self) -> Option<Self> {
+        match (
+            self.added.merge(other.added),
+            self.removed.merge(other.removed),
+        ) {
+            (None, None) => None,
+            (added, removed) => Some(Self {
+                added: added.unwrap_or_default(),
+                removed: removed.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+/// Identifies one add to an [`OrSet`]: the node that made it and how many
+/// adds that node had made.
+type Tag = (String, u64);
+
+/// An observed-remove set: removing an element only cancels the adds this
+/// replica has seen, so an add concurrent with a remove wins.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(bound(deserialize = "T: Eq + Hash + Deserialize<'de>"))]
+pub struct OrSet<T: Eq + Hash> {
+    added: HashSet<(T, Tag)>,
+    removed: HashSet<Tag>,
+    clock: GCounter,
+}
+
+impl<T: Eq + Hash> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            added: HashSet::new(),
+            removed: HashSet::new(),
+            clock: GCounter::default(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> OrSet<T> {
+    pub fn insert(&mut self, node_id: &str, value: T) -> Self {
+        let clock = self.clock.increment(node_id, 1);
+        let tag = (node_id.to_string(), self.clock.get(node_id));
+        self.added.insert((value.clone(), tag.clone()));
+
+        Self {
+            added: HashSet::from([(value, tag)]),
+            removed: HashSet::new(),
+            clock,
+        }
+    }
+
+    pub fn remove(&mut self, value: &T) -> Self {
+        let observed: HashSet<Tag> = self
+            .added
+            .iter()
+            .filter(|(v, _)| v == value)
+            .map(|(_, tag)| tag.clone())
+            .collect();
+        self.removed.extend(observed.iter().cloned());
+
+        Self {
+            added: HashSet::new(),
+            removed: observed,
+            clock: GCounter::default(),
+        }
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.iter().any(|v| v == value)
+    }
+
+    /// Every element in the set, once each.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut seen = HashSet::new();
+        self.added
+            .iter()
+            .filter(|(_, tag)| !self.removed.contains(tag))
+            .map(|(v, _)| v)
+            .filter(move |v| seen.insert(*v))
+    }
+}
+
+impl<T: Eq + Hash + Clone> Merge for OrSet<T> {
+    fn merge(&mut self, other: Self) -> Option<Self> {
+        let new = Self {
+            added: other
+                .added
+                .into_iter()
+                .filter(|entry| self.added.insert(entry.clone()))
+                .collect(),
+            removed: other
+                .removed
+                .into_iter()
+                .filter(|tag| self.removed.insert(tag.clone()))
+                .collect(),
+            clock: self.clock.merge(other.clock).unwrap_or_default(),
+        };
+
+        (new != Self::default()).then_some(new)
+    }
+}
+
+/// A register where the write with the highest timestamp wins, ties broken
+/// by node id. Timestamps must be unique per node, e.g. from a clock or
+/// `lin-tso`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LwwRegister<T> {
+    value: Option<T>,
+    timestamp: u64,
+    node_id: String,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            timestamp: 0,
+            node_id: String::new(),
+        }
+    }
+}
+
+impl<T: Clone> LwwRegister<T> {
+    /// Writes `value` unless a later write has already been seen, in which
+    /// case it returns `None`.
+    pub fn set(&mut self, value: T, timestamp: u64, node_id: &str) -> Option<Self> {
+        if (timestamp, node_id) <= (self.timestamp, self.node_id.as_str()) {
+            return None;
+        }
+
+        self.value = Some(value);
+        self.timestamp = timestamp;
+        self.node_id = node_id.to_string();
+        Some(self.clone())
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+impl<T: Clone> Merge for LwwRegister<T> {
+    fn merge(&mut self, other: Self) -> Option<Self> {
+        self.set(other.value?, other.timestamp, &other.node_id)
+    }
+}
+
+/// How many writes each node had seen when a value was written.
+type VersionVector = BTreeMap<String, u64>;
+
+/// A multi-value register: concurrent writes are all kept, for the reader
+/// to resolve, until a later write supersedes them.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MvRegister<T> {
+    /// Sorted by version, none of which dominates another.
+    values: Vec<(T, VersionVector)>,
+}
+
+impl<T> Default for MvRegister<T> {
+    fn default() -> Self {
+        Self { values: Vec::new() }
+    }
+}
+
+impl<T: Clone> MvRegister<T> {
+    /// Replaces every value this replica has seen with `value`.
+    pub fn set(&mut self, node_id: &str, value: T) -> Self {
+        let mut version = VersionVector::new();
+        for (_, seen) in self.values.drain(..) {
+            for (node, count) in seen {
+                let current = version.entry(node).or_default();
+                *current = (*current).max(count);
+            }
+        }
+        *version.entry(node_id.to_string()).or_default() += 1;
+
+        self.values.push((value, version));
+        self.clone()
+    }
+
+    /// The current values: one, or several written concurrently.
+    pub fn get(&self) -> impl Iterator<Item = &T> {
+        self.values.iter().map(|(v, _)| v)
+    }
+}
+
+impl<T: Clone> Merge for MvRegister<T> {
+    fn merge(&mut self, other: Self) -> Option<Self> {
+        let before: Vec<VersionVector> = self.values.iter().map(|(_, v)| v.clone()).collect();
+
+        self.values.extend(other.values);
+        // Each version is only ever written once, so equal versions hold
+        // equal values.
+        self.values.sort_by(|(_, a), (_, b)| a.cmp(b));
+        self.values.dedup_by(|(_, a), (_, b)| a == b);
+
+        let versions: Vec<VersionVector> = self.values.iter().map(|(_, v)| v.clone()).collect();
+        self.values
+            .retain(|(_, v)| !versions.iter().any(|other| dominates(other, v)));
+
+        // Whatever survived without being here before supersedes everything
+        // it displaced, so it is all another replica needs.
+        let new: Vec<(T, VersionVector)> = self
+            .values
+            .iter()
+            .filter(|(_, v)| !before.contains(v))
+            .cloned()
+            .collect();
+        (!new.is_empty()).then_some(Self { values: new })
+    }
+}
+
+/// Whether `a` has seen everything `b` has, and more.
+fn dominates(a: &VersionVector, b: &VersionVector) -> bool {
+    a != b
+        && b.iter()
+            .all(|(node, count)| a.get(node).is_some_and(|c| c >= count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::fmt::Debug;
+
+    const NODES: [&str; 3] = ["n1", "n2", "n3"];
+
+    /// Plays a random history over three replicas: `(i, i, x)` updates
+    /// replica `i` with `x`, and `(i, j, _)` merges replica `j` into `i`.
+    fn replicas<S: Merge + Clone + Default, D>(
+        history: &[(usize, usize, u8)],
+        update: impl Fn(&mut S, &str, u8) -> D,
+    ) -> [S; 3] {
+        let mut replicas: [S; 3] = Default::default();
+        for &(i, j, x) in history {
+            if i == j {
+                update(&mut replicas[i], NODES[i], x);
+            } else {
+                let other = replicas[j].clone();
+                replicas[i].merge(other);
+            }
+        }
+        replicas
+    }
+
+    fn merged<S: Merge + Clone>(a: &S, b: &S) -> S {
+        let mut a = a.clone();
+        a.merge(b.clone());
+        a
+    }
+
+    /// Checks the merge laws over replicas built from `history`, and that the
+    /// deltas a replica's updates return add up to its state.
+    fn assert_laws<S, D>(history: &[(usize, usize, u8)], update: impl Fn(&mut S, &str, u8) -> D)
+    where
+        S: Merge + Clone + Default + PartialEq + Debug,
+        D: Into<Option<S>>,
+    {
+        let [a, b, c] = replicas(history, &update);
+        assert_eq!(merged(&a, &b), merged(&b, &a), "not commutative");
+        assert_eq!(
+            merged(&merged(&a, &b), &c),
+            merged(&a, &merged(&b, &c)),
+            "not associative"
+        );
+        assert_eq!(merged(&a, &a), a, "not idempotent");
+
+        let mut ab = a.clone();
+        match ab.merge(b.clone()) {
+            Some(new) => assert_eq!(merged(&a, &new), ab, "new part is not what changed"),
+            None => assert_eq!(ab, a, "changed without saying so"),
+        }
+
+        let mut state = S::default();
+        let mut deltas = S::default();
+        for &(i, _, x) in history {
+            if let Some(delta) = update(&mut state, NODES[i], x).into() {
+                deltas.merge(delta);
+            }
+        }
+        assert_eq!(deltas, state, "deltas do not add up");
+    }
+
+    fn history() -> impl Strategy<Value = Vec<(usize, usize, u8)>> {
+        prop::collection::vec((0..3usize, 0..3usize, any::<u8>()), 0..40)
+    }
+
+    proptest! {
+        #[test]
+        fn test_gcounter_laws(history in history()) {
+            assert_laws(&history, |s: &mut GCounter, node, x| s.increment(node, x as u64));
+        }
+
+        #[test]
+        fn test_pncounter_laws(history in history()) {
+            assert_laws(&history, |s: &mut PnCounter, node, x| {
+                if x % 2 == 0 {
+                    s.increment(node, x as u64)
+                } else {
+                    s.decrement(node, x as u64)
+                }
+            });
+        }
+
+        #[test]
+        fn test_gset_laws(history in history()) {
+            assert_laws(&history, |s: &mut GSet<u8>, _, x| s.insert(x % 16));
+        }
+
+        #[test]
+        fn test_two_phase_set_laws(history in history()) {
+            assert_laws(&history, |s: &mut TwoPhaseSet<u8>, _, x| {
+                if x % 2 == 0 {
+                    Some(s.insert(x / 2 % 8))
+                } else {
+                    s.remove(&(x / 2 % 8))
+                }
+            });
+        }
+
+        #[test]
+        fn test_or_set_laws(history in history()) {
+            assert_laws(&history, |s: &mut OrSet<u8>, node, x| {
+                if x % 2 == 0 {
+                    s.insert(node, x / 2 % 8)
+                } else {
+                    s.remove(&(x / 2 % 8))
+                }
+            });
+        }
+
+        #[test]
+        fn test_lww_register_laws(history in history()) {
+            assert_laws(&history, |s: &mut LwwRegister<u8>, node, x| s.set(x, x as u64, node));
+        }
+
+        #[test]
+        fn test_mv_register_laws(history in history()) {
+            assert_laws(&history, |s: &mut MvRegister<u8>, node, x| s.set(node, x));
+        }
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut a = PnCounter::default();
+        let mut b = PnCounter::default();
+        a.increment("n1", 5);
+        b.increment("n2", 3);
+        b.decrement("n2", 4);
+
+        a.merge(b);
+        assert_eq!(a.value(), 4);
+        assert_eq!(a.increments.get("n1"), 5);
+        assert_eq!(a.increments.value(), 8);
+    }
+
+    #[test]
+    fn test_two_phase_set_removal_is_final() {
+        let mut set = TwoPhaseSet::default();
+        set.insert(1);
+        assert!(set.remove(&1).is_some());
+        set.insert(1);
+        assert!(!set.contains(&1));
+        assert!(set.remove(&2).is_none());
+    }
+
+    #[test]
+    fn test_or_set_add_wins() {
+        let mut a = OrSet::default();
+        a.insert("n1", "x");
+        let mut b = a.clone();
+
+        // n1 removes x while n2 concurrently adds it again.
+        a.remove(&"x");
+        b.insert("n2", "x");
+        assert!(!a.contains(&"x"));
+
+        a.merge(b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), [&"x"]);
+
+        a.remove(&"x");
+        assert!(!a.contains(&"x"));
+    }
+
+    #[test]
+    fn test_lww_register_keeps_latest() {
+        let mut a = LwwRegister::default();
+        let mut b = LwwRegister::default();
+        a.set("old", 1, "n1");
+        b.set("new", 2, "n2");
+        a.set("older", 0, "n1");
+
+        a.merge(b);
+        assert_eq!(a.get(), Some(&"new"));
+        assert_eq!(a.timestamp(), 2);
+    }
+
+    #[test]
+    fn test_mv_register_keeps_concurrent_writes() {
+        let mut a = MvRegister::default();
+        let mut b = MvRegister::default();
+        a.set("n1", 1);
+        b.set("n2", 2);
+
+        a.merge(b);
+        let mut values: Vec<_> = a.get().copied().collect();
+        values.sort();
+        assert_eq!(values, [1, 2]);
+
+        a.set("n1", 3);
+        assert_eq!(a.get().collect::<Vec<_>>(), [&3]);
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let mut counter = GCounter::default();
+        counter.increment("n1", 3);
+        assert_eq!(serde_json::to_string(&counter).unwrap(), r#"{"n1":3}"#);
+
+        let mut set = OrSet::default();
+        set.insert("n1", 7u64);
+        set.remove(&7);
+        set.insert("n2", 7);
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(serde_json::from_str::<OrSet<u64>>(&json).unwrap(), set);
+
+        let mut register = MvRegister::default();
+        register.set("n1", "a".to_string());
+        let json = serde_json::to_string(&register).unwrap();
+        assert_eq!(
+            serde_json::from_str::<MvRegister<String>>(&json).unwrap(),
+            register
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{Envelope, MessageID};
+
+/// How long a request may go unanswered before a retry of it is handed to
+/// the node after all. The handler may have failed, or dropped it after a
+/// service timed out, and then no reply is ever coming. Longer than any
+/// node takes to answer, since a retry let through is handled twice.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) enum Seen {
+    /// First time, or unanswered for too long: hand it to the node.
+    New,
+    /// Already being handled, and nothing has been sent in reply yet.
+    Pending,
+    /// Already answered with this line.
+    Replied(Vec<u8>),
+}
+
+/// Remembers the last `window` requests, by sender and `msg_id`, along with
+/// what the node replied, so a retried request is answered the same way
+/// rather than handled twice.
+///
+/// Replies are picked out of the outgoing batches by `in_reply_to`, so a
+/// node that answers a request events later, e.g. once a service has
+/// responded, is covered too.
+pub(crate) struct Dedup {
+    window: usize,
+    order: VecDeque<(String, MessageID)>,
+    replies: HashMap<(String, MessageID), Reply>,
+}
+
+enum Reply {
+    /// Handed to the node at this time, and not answered yet.
+    Pending(Instant),
+    Sent(Vec<u8>),
+}
+
+impl Dedup {
+    /// `None` for a window of 0, which would remember nothing.
+    pub(crate) fn new(window: usize) -> Option<Self> {
+        (window > 0).then(|| Self {
+            window,
+            order: VecDeque::new(),
+            replies: HashMap::new(),
+        })
+    }
+
+    /// Looks up a request, remembering it if it is new.
+    pub(crate) fn check(&mut self, src: &str, msg_id: MessageID, now: Instant) -> Seen {
+        let key = (src.to_string(), msg_id);
+        match self.replies.get_mut(&key) {
+            Some(Reply::Sent(reply)) => return Seen::Replied(reply.clone()),
+            Some(Reply::Pending(since)) if now.duration_since(*since) < PENDING_TIMEOUT => {
+                return Seen::Pending;
+            }
+            Some(Reply::Pending(since)) => {
+                *since = now;
+                return Seen::New;
+            }
+            None => {}
+        }
+
+        while self.order.len() >= self.window
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.replies.remove(&oldest);
+        }
+        self.order.push_back(key.clone());
+        self.replies.insert(key, Reply::Pending(now));
+
+        Seen::New
+    }
+
+    /// Records the replies to remembered requests in a batch of outgoing
+    /// lines.
+    pub(crate) fn observe(&mut self, batch: &[u8]) {
+        for line in batch.split_inclusive(|&b| b == b'\n') {
+            let Ok(envelope) = serde_json::from_slice::<Envelope>(line) else {
+                continue;
+            };
+            let Some(in_reply_to) = envelope.ids().ok().and_then(|ids| ids.in_reply_to) else {
+                continue;
+            };
+
+            if let Some(reply @ Reply::Pending(_)) = self
+                .replies
+                .get_mut(&(envelope.dst.into_owned(), in_reply_to))
+            {
+                *reply = Reply::Sent(line.to_vec());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replays_reply() {
+        let mut dedup = Dedup::new(10).unwrap();
+        let now = Instant::now();
+
+        assert!(matches!(dedup.check("c1", 1, now), Seen::New));
+        assert!(matches!(dedup.check("c1", 1, now), Seen::Pending));
+        assert!(matches!(dedup.check("c2", 1, now), Seen::New));
+
+        let batch = concat!(
+            r#"{"src":"n1","dest":"n2","body":{"type":"gossip","msg_id":null}}"#,
+            "\n",
+            r#"{"src":"n1","dest":"c1","body":{"type":"add_ok","msg_id":4,"in_reply_to":1}}"#,
+            "\n",
+            r#"{"src":"n1","dest":"c3","body":{"type":"add_ok","msg_id":5,"in_reply_to":1}}"#,
+            "\n",
+        );
+        dedup.observe(batch.as_bytes());
+
+        let Seen::Replied(reply) = dedup.check("c1", 1, now) else {
+            panic!("expected the cached reply");
+        };
+        assert_eq!(
+            reply,
+            br#"{"src":"n1","dest":"c1","body":{"type":"add_ok","msg_id":4,"in_reply_to":1}}
+"#
+        );
+        assert!(matches!(dedup.check("c2", 1, now), Seen::Pending));
+        assert!(matches!(dedup.check("c3", 1, now), Seen::New));
+
+        // The first reply sticks.
+        dedup.observe(
+            br#"{"src":"n1","dest":"c1","body":{"type":"add_ok","msg_id":6,"in_reply_to":1}}
+"#,
+        );
+        assert!(matches!(dedup.check("c1", 1, now), Seen::Replied(r) if r == reply));
+    }
+
+    #[test]
+    fn test_forgets_outside_window() {
+        let mut dedup = Dedup::new(2).unwrap();
+        let now = Instant::now();
+
+        for msg_id in 1..=3 {
+            assert!(matches!(dedup.check("c1", msg_id, now), Seen::New));
+        }
+        assert!(matches!(dedup.check("c1", 3, now), Seen::Pending));
+        assert!(matches!(dedup.check("c1", 1, now), Seen::New));
+    }
+
+    #[test]
+    fn test_zero_window_is_off() {
+        assert!(Dedup::new(0).is_none());
+    }
+
+    #[test]
+    fn test_lets_retry_through_once_unanswered_too_long() {
+        let mut dedup = Dedup::new(10).unwrap();
+        let now = Instant::now();
+
+        assert!(matches!(dedup.check("c1", 1, now), Seen::New));
+        let later = now + PENDING_TIMEOUT;
+        assert!(matches!(
+            dedup.check("c1", 1, later - Duration::from_millis(1)),
+            Seen::Pending
+        ));
+        assert!(matches!(dedup.check("c1", 1, later), Seen::New));
+        // The retry now has its own deadline.
+        assert!(matches!(dedup.check("c1", 1, later), Seen::Pending));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::cluster::Cluster;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Alive,
+    Suspected,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusChange {
+    pub peer: String,
+    pub status: Status,
+}
+
+/// The phi accrual detector from Hayashibara et al., as used by Cassandra
+/// and Akka: rather than a fixed cut-off, it learns how far apart messages
+/// from each peer usually are and suspects a peer once the current silence
+/// becomes unlikely enough.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhiAccrual {
+    /// Suspicion level at which a peer is suspected; 8 means roughly a one in
+    /// a hundred million chance that a live peer would have been this quiet.
+    pub threshold: f64,
+    /// How many recent gaps between messages to learn from.
+    pub window: usize,
+    /// Floor on the learned deviation, so that very regular traffic does not
+    /// make the detector jumpy.
+    pub min_std_dev: Duration,
+    /// Extra silence to tolerate on top of the learned gaps, e.g. for GC
+    /// pauses or a slow network.
+    pub acceptable_pause: Duration,
+}
+
+impl Default for PhiAccrual {
+    fn default() -> Self {
+        Self {
+            threshold: 8.0,
+            window: 100,
+            min_std_dev: Duration::from_millis(50),
+            acceptable_pause: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Detection {
+    /// Suspect a peer after this long without hearing from it.
+    Timeout(Duration),
+    PhiAccrual(PhiAccrual),
+}
+
+struct Peer {
+    status: Status,
+    last_heard: Instant,
+    last_sent: Instant,
+    gaps: VecDeque<f64>,
+}
+
+/// Tracks whether each peer in the cluster is alive, based on when we last
+/// heard from it.
+///
+/// Any message from a peer counts, so nodes just report what they receive
+/// with [`FailureDetector::heard_from`]; the pings from
+/// [`FailureDetector::pings_due`] are only needed for peers we have not
+/// otherwise talked to lately. Every peer starts out alive.
+pub struct FailureDetector {
+    detection: Detection,
+    ping_interval: Duration,
+    peers: HashMap<String, Peer>,
+}
+
+impl FailureDetector {
+    pub fn new(
+        cluster: &Cluster,
+        ping_interval: Duration,
+        detection: Detection,
+        now: Instant,
+    ) -> Self {
+        Self {
+            detection,
+            ping_interval,
+            peers: cluster
+                .peers()
+                .map(|peer| {
+                    let state = Peer {
+                        status: Status::Alive,
+                        last_heard: now,
+                        last_sent: now,
+                        gaps: VecDeque::new(),
+                    };
+                    (peer.clone(), state)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn status(&self, peer: &str) -> Option<Status> {
+        self.peers.get(peer).map(|p| p.status)
+    }
+
+    pub fn is_alive(&self, peer: &str) -> bool {
+        self.status(peer) == Some(Status::Alive)
+    }
+
+    /// Records a message from `peer`. Returns the change if it had been
+    /// suspected. Messages from anything outside the cluster are ignored.
+    pub fn heard_from(&mut self, peer: &str, now: Instant) -> Option<StatusChange> {
+        let window = match self.detection {
+            Detection::PhiAccrual(phi) => phi.window,
+            Detection::Timeout(_) => 0,
+        };
+        let state = self.peers.get_mut(peer)?;
+
+        if window > 0 {
+            if state.gaps.len() == window {
+                state.gaps.pop_front();
+            }
+            state
+                .gaps
+                .push_back(now.duration_since(state.last_heard).as_secs_f64());
+        }
+        state.last_heard = now;
+
+        (state.status == Status::Suspected).then(|| {
+            state.status = Status::Alive;
+            StatusChange {
+                peer: peer.to_string(),
+                status: Status::Alive,
+            }
+        })
+    }
+
+    /// Records that we sent `peer` something, which makes a ping unnecessary.
+    pub fn sent_to(&mut self, peer: &str, now: Instant) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.last_sent = now;
+        }
+    }
+
+    /// Peers we have sent nothing to for a ping interval. The caller is
+    /// expected to ping each of them, which counts as sending.
+    pub fn pings_due(&mut self, now: Instant) -> Vec<String> {
+        let mut due: Vec<String> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, state)| now.duration_since(state.last_sent) >= self.ping_interval)
+            .map(|(peer, state)| {
+                state.last_sent = now;
+                peer.clone()
+            })
+            .collect();
+        due.sort();
+        due
+    }
+
+    /// Re-evaluates every peer, suspecting those that have gone quiet.
+    pub fn check(&mut self, now: Instant) -> Vec<StatusChange> {
+        let mut changes = Vec::new();
+
+        for (peer, state) in &mut self.peers {
+            if state.status == Status::Suspected {
+                continue;
+            }
+
+            let suspected = match self.detection {
+                Detection::Timeout(timeout) => now.duration_since(state.last_heard) >= timeout,
+                Detection::PhiAccrual(config) => {
+                    phi(state, config, self.ping_interval, now) >= config.threshold
+                }
+            };
+
+            if suspected {
+                state.status = Status::Suspected;
+                changes.push(StatusChange {
+                    peer: peer.clone(),
+                    status: Status::Suspected,
+                });
+            }
+        }
+
+        changes.sort_by(|a, b| a.peer.cmp(&b.peer));
+        changes
+    }
+
+    /// How suspicious the phi accrual detector is of `peer` right now, or
+    /// `None` for an unknown peer or when detecting by timeout.
+    pub fn phi(&self, peer: &str, now: Instant) -> Option<f64> {
+        match self.detection {
+            Detection::PhiAccrual(config) => self
+                .peers
+                .get(peer)
+                .map(|state| phi(state, config, self.ping_interval, now)),
+            Detection::Timeout(_) => None,
+        }
+    }
+}
+
+fn phi(peer: &Peer, config: PhiAccrual, ping_interval: Duration, now: Instant) -> f64 {
+    // Until we have heard anything, assume gaps of about a ping interval.
+    let (mean, std_dev) = if peer.gaps.is_empty() {
+        let expected = ping_interval.as_secs_f64();
+        (expected, expected / 4.0)
+    } else {
+        let n = peer.gaps.len() as f64;
+        let mean = peer.gaps.iter().sum::<f64>() / n;
+        let variance = peer.gaps.iter().map(|g| (g - mean).powi(2)).sum::<f64>() / n;
+        (mean, variance.sqrt())
+    };
+
+    let mean = mean + config.acceptable_pause.as_secs_f64();
+    let std_dev = std_dev.max(config.min_std_dev.as_secs_f64());
+    let elapsed = now.duration_since(peer.last_heard).as_secs_f64();
+
+    // Logistic approximation of the normal CDF, as in Akka.
+    let y = (elapsed - mean) / std_dev;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InitBody;
+
+    fn cluster() -> Cluster {
+        Cluster::from(InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        })
+    }
+
+    const PING: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn test_timeout_suspects_silent_peers() {
+        let start = Instant::now();
+        let mut detector = FailureDetector::new(
+            &cluster(),
+            PING,
+            Detection::Timeout(Duration::from_secs(1)),
+            start,
+        );
+
+        assert_eq!(detector.status("n1"), None);
+        assert!(detector.is_alive("n2"));
+
+        let later = start + Duration::from_millis(600);
+        assert_eq!(detector.heard_from("n2", later), None);
+        assert_eq!(detector.heard_from("c1", later), None);
+
+        assert_eq!(
+            detector.check(start + Duration::from_secs(1)),
+            [StatusChange {
+                peer: "n3".to_string(),
+                status: Status::Suspected,
+            }]
+        );
+        assert!(detector.check(start + Duration::from_secs(1)).is_empty());
+
+        let back = start + Duration::from_secs(2);
+        assert_eq!(
+            detector.heard_from("n3", back),
+            Some(StatusChange {
+                peer: "n3".to_string(),
+                status: Status::Alive,
+            })
+        );
+        assert!(detector.is_alive("n3"));
+    }
+
+    #[test]
+    fn test_phi_accrual_learns_gaps() {
+        let start = Instant::now();
+        let mut detector = FailureDetector::new(
+            &cluster(),
+            PING,
+            Detection::PhiAccrual(PhiAccrual::default()),
+            start,
+        );
+
+        // n2 speaks every 100ms, n3 every 500ms.
+        let mut now = start;
+        for i in 1..=20 {
+            now = start + PING * i;
+            detector.heard_from("n2", now);
+            if i % 5 == 0 {
+                detector.heard_from("n3", now);
+            }
+        }
+
+        let quiet = now + Duration::from_millis(400);
+        assert!(detector.phi("n2", quiet).unwrap() > detector.phi("n3", quiet).unwrap());
+        assert_eq!(
+            detector.check(quiet),
+            [StatusChange {
+                peer: "n2".to_string(),
+                status: Status::Suspected,
+            }]
+        );
+
+        let silent = now + Duration::from_secs(2);
+        assert!(detector.phi("n3", silent).unwrap() >= PhiAccrual::default().threshold);
+        assert_eq!(
+            detector.check(silent),
+            [StatusChange {
+                peer: "n3".to_string(),
+                status: Status::Suspected,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_pings_only_quiet_links() {
+        let start = Instant::now();
+        let mut detector = FailureDetector::new(
+            &cluster(),
+            PING,
+            Detection::Timeout(Duration::from_secs(1)),
+            start,
+        );
+
+        assert!(detector.pings_due(start).is_empty());
+
+        detector.sent_to("n2", start + PING / 2);
+        assert_eq!(detector.pings_due(start + PING), ["n3"]);
+        assert!(detector.pings_due(start + PING).is_empty());
+        assert_eq!(detector.pings_due(start + PING * 2), ["n2", "n3"]);
+    }
+}
@@ -0,0 +1,643 @@
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Cluster;
+
+pub type Term = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    /// How often a leader reasserts itself to its followers.
+    pub heartbeat: Duration,
+    /// How long a follower waits to hear from a leader before standing for
+    /// election. Each wait is stretched by a random amount of up to the same
+    /// again, so that candidates rarely split the vote.
+    ///
+    /// A leader that has not heard back from a majority for this long steps
+    /// down, so a partitioned leader gives up rather than lingering.
+    pub election: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            heartbeat: Duration::from_millis(50),
+            election: Duration::from_millis(300),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RequestVoteBody<P> {
+    pub term: Term,
+    /// How up to date the candidate is; votes only go to candidates at least
+    /// as far along as the voter.
+    pub position: P,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VoteBody {
+    pub term: Term,
+    pub granted: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HeartbeatBody {
+    pub term: Term,
+}
+
+/// Messages exchanged between peers to agree on a leader. Embed them in a
+/// node's own message type with `#[serde(untagged)]`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ElectionMessage<P = ()> {
+    RequestVote(RequestVoteBody<P>),
+    Vote(VoteBody),
+    Heartbeat(HeartbeatBody),
+    HeartbeatOk(HeartbeatBody),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeaderChange {
+    pub term: Term,
+    /// `None` while the cluster is between leaders, as far as this node can
+    /// tell.
+    pub leader: Option<String>,
+}
+
+/// What the node has to act on after driving the election.
+#[derive(Debug, PartialEq)]
+pub struct Step<P = ()> {
+    /// Messages to send, keyed by destination.
+    pub messages: Vec<(String, ElectionMessage<P>)>,
+    /// Set whenever this node's view of the leader changed, for the node to
+    /// react to.
+    pub leader_change: Option<LeaderChange>,
+}
+
+impl<P> Default for Step<P> {
+    fn default() -> Self {
+        Self {
+            messages: Vec::new(),
+            leader_change: None,
+        }
+    }
+}
+
+/// Term-based leader election among the members of a [`Cluster`], as in
+/// Raft.
+///
+/// At most one leader is elected per term: each node votes once per term,
+/// and a candidate needs a majority. The state machine does no I/O; the node
+/// feeds it ticks and peer messages and sends whatever [`Step`] comes back.
+///
+/// `P` is the position candidates are compared by when voting. Plain leader
+/// election has none, hence `()`; a replicated log would use its last entry.
+pub struct Election<P = ()> {
+    cluster: Cluster,
+    timeouts: Timeouts,
+    term: Term,
+    role: Role,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    votes: HashSet<String>,
+    deadline: Instant,
+    next_heartbeat: Instant,
+    leader_since: Instant,
+    acks: HashMap<String, Instant>,
+    rng: u64,
+    position: PhantomData<P>,
+}
+
+impl<P: Clone + PartialOrd> Election<P> {
+    pub fn new(cluster: Cluster, timeouts: Timeouts, now: Instant) -> Self {
+        let mut election = Self {
+            rng: 0x9e3779b97f4a7c15 ^ (cluster.index() as u64 + 1),
+            cluster,
+            timeouts,
+            term: 0,
+            role: Role::Follower,
+            voted_for: None,
+            leader: None,
+            votes: HashSet::new(),
+            deadline: now,
+            next_heartbeat: now,
+            leader_since: now,
+            acks: HashMap::new(),
+            position: PhantomData,
+        };
+        election.reset_deadline(now);
+
+        election
+    }
+
+    pub fn term(&self) -> Term {
+        self.term
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn cluster(&self) -> &Cluster {
+        &self.cluster
+    }
+
+    /// Who this node voted for in the current term, if anyone.
+    pub fn voted_for(&self) -> Option<&str> {
+        self.voted_for.as_deref()
+    }
+
+    /// Picks up the term and vote a node had before it restarted, as a
+    /// follower. Both must have been made durable before any message that
+    /// depended on them was sent, or the node could vote twice in one term.
+    pub fn recover(&mut self, term: Term, voted_for: Option<String>) {
+        self.term = term;
+        self.voted_for = voted_for;
+        self.role = Role::Follower;
+        self.leader = None;
+        self.votes.clear();
+    }
+
+    /// Drives timeouts: followers that have not heard from a leader stand
+    /// for election, and leaders send heartbeats or step down.
+    pub fn tick(&mut self, now: Instant, position: &P) -> Step<P> {
+        let before = self.view();
+        let mut step = Step::default();
+
+        match self.role {
+            Role::Leader => {
+                let heard_from = self
+                    .acks
+                    .values()
+                    .filter(|&&at| now.duration_since(at) < self.timeouts.election)
+                    .count();
+
+                if now.duration_since(self.leader_since) >= self.timeouts.election
+                    && heard_from + 1 < self.quorum()
+                {
+                    self.role = Role::Follower;
+                    self.leader = None;
+                    self.reset_deadline(now);
+                } else if now >= self.next_heartbeat {
+                    self.heartbeat(now, &mut step);
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.deadline {
+                    self.campaign(now, position, &mut step);
+                }
+            }
+        }
+
+        self.finish(before, step)
+    }
+
+    pub fn on_message(
+        &mut self,
+        from: &str,
+        message: ElectionMessage<P>,
+        now: Instant,
+        position: &P,
+    ) -> Step<P> {
+        let before = self.view();
+        let mut step = Step::default();
+
+        match message {
+            ElectionMessage::RequestVote(body) => {
+                self.observe_term(body.term, now);
+
+                let granted = body.term == self.term
+                    && self.voted_for.as_deref().is_none_or(|v| v == from)
+                    && body.position >= *position;
+
+                if granted {
+                    self.voted_for = Some(from.to_string());
+                    self.reset_deadline(now);
+                }
+
+                step.messages.push((
+                    from.to_string(),
+                    ElectionMessage::Vote(VoteBody {
+                        term: self.term,
+                        granted,
+                    }),
+                ));
+            }
+            ElectionMessage::Vote(body) => {
+                self.observe_term(body.term, now);
+
+                if self.role == Role::Candidate && body.term == self.term && body.granted {
+                    self.votes.insert(from.to_string());
+
+                    if self.votes.len() >= self.quorum() {
+                        self.lead(now, &mut step);
+                    }
+                }
+            }
+            ElectionMessage::Heartbeat(body) => {
+                if self.accept_leader(from, body.term, now) {
+                    step.messages.push((
+                        from.to_string(),
+                        ElectionMessage::HeartbeatOk(HeartbeatBody { term: body.term }),
+                    ));
+                } else {
+                    // Let the stale leader know it has been superseded.
+                    step.messages.push((
+                        from.to_string(),
+                        ElectionMessage::HeartbeatOk(HeartbeatBody { term: self.term }),
+                    ));
+                }
+            }
+            ElectionMessage::HeartbeatOk(body) => {
+                self.observe_term(body.term, now);
+
+                if self.role == Role::Leader && body.term == self.term {
+                    self.acks.insert(from.to_string(), now);
+                }
+            }
+        }
+
+        self.finish(before, step)
+    }
+
+    /// Records that `from` is acting as leader for `term`, as a heartbeat
+    /// does. Returns whether `from` is accepted as the leader; it is not if
+    /// this node has already moved on to a later term.
+    ///
+    /// Protocols that carry leadership on their own messages (such as log
+    /// replication) call this instead of sending separate heartbeats.
+    pub fn accept_leader(&mut self, from: &str, term: Term, now: Instant) -> bool {
+        if term < self.term {
+            return false;
+        }
+
+        self.observe_term(term, now);
+        self.role = Role::Follower;
+        self.leader = Some(from.to_string());
+        self.reset_deadline(now);
+
+        true
+    }
+
+    /// Records an acknowledgement from a follower in the current term, which
+    /// keeps a leader from stepping down.
+    pub fn acknowledge(&mut self, from: &str, now: Instant) {
+        if self.role == Role::Leader {
+            self.acks.insert(from.to_string(), now);
+        }
+    }
+
+    /// Steps down to follower if `term` is newer than ours. Returns whether
+    /// it was.
+    pub fn observe_term(&mut self, term: Term, now: Instant) -> bool {
+        if term <= self.term {
+            return false;
+        }
+
+        self.term = term;
+        self.role = Role::Follower;
+        self.voted_for = None;
+        self.leader = None;
+        self.votes.clear();
+        self.reset_deadline(now);
+
+        true
+    }
+
+    /// How this node's view of the leader has changed since `before`, for
+    /// changes made outside of [`Election::tick`] and
+    /// [`Election::on_message`].
+    pub fn leader_change_since(&self, before: (Term, Option<String>)) -> Option<LeaderChange> {
+        let (term, leader) = before;
+
+        (self.leader != leader || (self.leader.is_some() && self.term != term)).then(|| {
+            LeaderChange {
+                term: self.term,
+                leader: self.leader.clone(),
+            }
+        })
+    }
+
+    /// The term and leader as this node sees them, to pass to
+    /// [`Election::leader_change_since`].
+    pub fn view(&self) -> (Term, Option<String>) {
+        (self.term, self.leader.clone())
+    }
+
+    fn campaign(&mut self, now: Instant, position: &P, step: &mut Step<P>) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.cluster.node_id().to_string());
+        self.leader = None;
+        self.votes = HashSet::from([self.cluster.node_id().to_string()]);
+        self.reset_deadline(now);
+
+        for peer in self.cluster.peers() {
+            step.messages.push((
+                peer.clone(),
+                ElectionMessage::RequestVote(RequestVoteBody {
+                    term: self.term,
+                    position: position.clone(),
+                }),
+            ));
+        }
+
+        if self.votes.len() >= self.quorum() {
+            self.lead(now, step);
+        }
+    }
+
+    fn lead(&mut self, now: Instant, step: &mut Step<P>) {
+        self.role = Role::Leader;
+        self.leader = Some(self.cluster.node_id().to_string());
+        self.leader_since = now;
+        self.acks.clear();
+
+        self.heartbeat(now, step);
+    }
+
+    fn heartbeat(&mut self, now: Instant, step: &mut Step<P>) {
+        self.next_heartbeat = now + self.timeouts.heartbeat;
+
+        for peer in self.cluster.peers() {
+            step.messages.push((
+                peer.clone(),
+                ElectionMessage::Heartbeat(HeartbeatBody { term: self.term }),
+            ));
+        }
+    }
+
+    fn finish(&self, before: (Term, Option<String>), mut step: Step<P>) -> Step<P> {
+        step.leader_change = self.leader_change_since(before);
+        step
+    }
+
+    fn quorum(&self) -> usize {
+        self.cluster.len() / 2 + 1
+    }
+
+    fn reset_deadline(&mut self, now: Instant) {
+        // xorshift64*: cheap, and seeded per node so peers time out apart.
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let jitter = self.rng.wrapping_mul(0x2545f4914f6cdd1d) % 1000;
+
+        self.deadline =
+            now + self.timeouts.election + self.timeouts.election * jitter as u32 / 1000;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        InitBody,
+        sim::{Network, Peer},
+    };
+
+    fn cluster(node_id: &str, n: usize) -> Cluster {
+        Cluster::from(InitBody {
+            node_id: node_id.to_string(),
+            node_ids: (1..=n).map(|i| format!("n{i}")).collect(),
+        })
+    }
+
+    struct Candidate {
+        election: Election,
+        changes: Vec<LeaderChange>,
+    }
+
+    impl Peer for Candidate {
+        type Message = ElectionMessage;
+
+        fn tick(&mut self, now: Instant) -> Vec<(String, ElectionMessage)> {
+            let step = self.election.tick(now, &());
+            self.changes.extend(step.leader_change);
+            step.messages
+        }
+
+        fn receive(
+            &mut self,
+            from: &str,
+            message: ElectionMessage,
+            now: Instant,
+        ) -> Vec<(String, ElectionMessage)> {
+            let step = self.election.on_message(from, message, now, &());
+            self.changes.extend(step.leader_change);
+            step.messages
+        }
+    }
+
+    struct Sim {
+        network: Network<Candidate>,
+        leaders: HashMap<Term, String>,
+    }
+
+    impl Sim {
+        fn new(n: usize) -> Self {
+            let now = Instant::now();
+
+            Self {
+                network: Network::new(
+                    now,
+                    (1..=n).map(|i| {
+                        let id = format!("n{i}");
+                        let candidate = Candidate {
+                            election: Election::new(cluster(&id, n), Timeouts::default(), now),
+                            changes: Vec::new(),
+                        };
+                        (id, candidate)
+                    }),
+                ),
+                leaders: HashMap::new(),
+            }
+        }
+
+        fn run(&mut self, duration: Duration) {
+            let leaders = &mut self.leaders;
+
+            self.network.run(duration, |id, node| {
+                if node.election.is_leader() {
+                    let term = node.election.term();
+                    let leader = leaders.entry(term).or_insert(id.to_string());
+                    assert_eq!(leader, id, "two leaders in term {term}");
+                }
+            });
+        }
+
+        fn node(&self, id: &str) -> &Election {
+            &self.network.nodes[id].election
+        }
+
+        fn leaders(&self) -> Vec<&str> {
+            self.network
+                .nodes
+                .iter()
+                .filter(|(_, n)| n.election.is_leader())
+                .map(|(id, _)| id.as_str())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_elects_one_leader() {
+        let mut sim = Sim::new(5);
+        sim.run(Duration::from_secs(2));
+
+        let leaders = sim.leaders();
+        assert_eq!(leaders.len(), 1);
+
+        for node in sim.network.nodes.values() {
+            assert_eq!(node.election.leader(), Some(leaders[0]));
+            assert_eq!(
+                node.changes.last(),
+                Some(&LeaderChange {
+                    term: node.election.term(),
+                    leader: Some(leaders[0].to_string()),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_single_node_leads_itself() {
+        let mut sim = Sim::new(1);
+        sim.run(Duration::from_secs(1));
+
+        assert_eq!(sim.leaders(), ["n1"]);
+    }
+
+    #[test]
+    fn test_partitioned_leader_is_replaced() {
+        let mut sim = Sim::new(5);
+        sim.run(Duration::from_secs(2));
+
+        let old = sim.leaders()[0].to_string();
+        let old_term = sim.node(&old).term();
+        let rest: Vec<String> = sim
+            .network
+            .nodes
+            .keys()
+            .filter(|&id| *id != old)
+            .cloned()
+            .collect();
+
+        sim.network.partition(&[
+            &[&old],
+            &rest.iter().map(String::as_str).collect::<Vec<_>>(),
+        ]);
+        sim.run(Duration::from_secs(2));
+
+        let leaders = sim.leaders();
+        assert_eq!(leaders.len(), 1);
+        assert_ne!(leaders[0], old);
+        assert!(sim.node(leaders[0]).term() > old_term);
+        assert_eq!(sim.node(&old).leader(), None);
+
+        sim.network.heal();
+        sim.run(Duration::from_secs(3));
+
+        let leaders = sim.leaders();
+        assert_eq!(leaders.len(), 1);
+        for node in sim.network.nodes.values() {
+            assert_eq!(node.election.leader(), Some(leaders[0]));
+        }
+    }
+
+    #[test]
+    fn test_minority_cannot_elect() {
+        let mut sim = Sim::new(5);
+        sim.network.partition(&[&["n1", "n2"], &["n3", "n4", "n5"]]);
+        sim.run(Duration::from_secs(3));
+
+        let leaders = sim.leaders();
+        assert_eq!(leaders.len(), 1);
+        assert!(["n3", "n4", "n5"].contains(&leaders[0]));
+        assert_eq!(sim.node("n1").leader(), None);
+        assert_eq!(sim.node("n2").leader(), None);
+    }
+
+    #[test]
+    fn test_votes_once_per_term() {
+        let now = Instant::now();
+        let mut election: Election = Election::new(cluster("n1", 3), Timeouts::default(), now);
+
+        let request = ElectionMessage::RequestVote(RequestVoteBody {
+            term: 1,
+            position: (),
+        });
+
+        let step = election.on_message("n2", request.clone(), now, &());
+        assert_eq!(
+            step.messages,
+            [(
+                "n2".to_string(),
+                ElectionMessage::Vote(VoteBody {
+                    term: 1,
+                    granted: true,
+                })
+            )]
+        );
+
+        let step = election.on_message("n3", request, now, &());
+        assert_eq!(
+            step.messages,
+            [(
+                "n3".to_string(),
+                ElectionMessage::Vote(VoteBody {
+                    term: 1,
+                    granted: false,
+                })
+            )]
+        );
+    }
+
+    #[test]
+    fn test_rejects_candidates_behind_it() {
+        let now = Instant::now();
+        let mut election = Election::new(cluster("n1", 3), Timeouts::default(), now);
+
+        let step = election.on_message(
+            "n2",
+            ElectionMessage::RequestVote(RequestVoteBody {
+                term: 1,
+                position: 3,
+            }),
+            now,
+            &4,
+        );
+
+        assert_eq!(
+            step.messages,
+            [(
+                "n2".to_string(),
+                ElectionMessage::Vote(VoteBody {
+                    term: 1,
+                    granted: false,
+                })
+            )]
+        );
+    }
+}
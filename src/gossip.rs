@@ -0,0 +1,747 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cluster::Cluster, crdt::Merge};
+
+/// Which way state flows in a round of gossip.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mode {
+    /// Send our state to the chosen peers.
+    #[default]
+    Push,
+    /// Ask the chosen peers for their state.
+    Pull,
+    /// Send our state and have the chosen peers answer with theirs.
+    PushPull,
+    /// Send each peer only the deltas it has not acknowledged yet, falling
+    /// back to our whole state when it has missed more than we kept.
+    Delta,
+}
+
+/// How the peers for each round are picked when `fanout` is smaller than
+/// the number of peers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PeerSelection {
+    #[default]
+    Random,
+    /// Take turns, so every peer is reached within a bounded number of
+    /// rounds.
+    RoundRobin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    /// Time between rounds.
+    pub interval: Duration,
+    /// How many peers to gossip with each round.
+    pub fanout: usize,
+    pub mode: Mode,
+    pub selection: PeerSelection,
+    /// How many deltas to keep for peers that have not acknowledged them,
+    /// e.g. across a partition, before they need the whole state instead.
+    pub max_deltas: usize,
+    /// In [`Mode::Delta`], whether to pass on what we learn from one peer to
+    /// the others. Turn it off when every node gossips with every other,
+    /// since each delta then reaches everyone straight from where it began.
+    pub relay: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(500),
+            fanout: usize::MAX,
+            mode: Mode::default(),
+            selection: PeerSelection::default(),
+            max_deltas: 1000,
+            relay: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GossipBody<S> {
+    pub state: S,
+}
+
+/// Deltas, or a whole state, covering everything up to `seq`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DeltaBody<S> {
+    pub state: S,
+    pub seq: u64,
+    /// Acknowledges the recipient's deltas up to here, saving a separate
+    /// [`GossipMessage::Ack`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AckBody {
+    pub seq: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum GossipMessage<S> {
+    #[serde(rename = "gossip")]
+    Push(GossipBody<S>),
+    #[serde(rename = "gossip_pull")]
+    Pull,
+    #[serde(rename = "gossip_push_pull")]
+    PushPull(GossipBody<S>),
+    #[serde(rename = "gossip_delta")]
+    Delta(DeltaBody<S>),
+    #[serde(rename = "gossip_ack")]
+    Ack(AckBody),
+}
+
+/// Periodically exchanges a replicated state with peers until every replica
+/// has merged in everyone else's changes.
+///
+/// Nodes change the state locally through [`Gossip::update`], call
+/// [`Gossip::tick`] regularly and hand it whatever [`GossipMessage`]s arrive;
+/// both return the messages to send. Repeating full rounds is what makes
+/// this anti-entropy: a lost message or a healed partition is made up for in
+/// a later round.
+///
+/// In [`Mode::Delta`] the rounds carry only what each peer is missing. Every
+/// delta, ours or the new part of one a peer sent, is numbered and kept
+/// until all peers have acknowledged it. Deltas are passed on so they reach
+/// nodes that are not our peers, but never back to where they came from.
+/// Acknowledgements ride along with the next round's delta to that peer, and
+/// only go on their own, once per round, to peers that get no delta.
+pub struct Gossip<S> {
+    config: Config,
+    peers: Vec<String>,
+    state: S,
+    last_round: Instant,
+    next_peer: usize,
+    rng: u64,
+    /// Numbered deltas and the peer each came from, oldest first.
+    deltas: VecDeque<(u64, Option<String>, S)>,
+    /// Deltas numbered below this have been dropped; peers that have not
+    /// acknowledged up to here need our whole state.
+    base_seq: u64,
+    next_seq: u64,
+    /// The sequence number up to which each peer has acknowledged.
+    acked: HashMap<String, u64>,
+    /// The latest sequence number from each peer that we owe an ack for.
+    unacked: HashMap<String, u64>,
+}
+
+impl<S: Merge + Clone + Default + PartialEq> Gossip<S> {
+    pub fn new(
+        cluster: &Cluster,
+        peers: Vec<String>,
+        state: S,
+        config: Config,
+        now: Instant,
+    ) -> Self {
+        Self {
+            config,
+            peers,
+            state,
+            last_round: now,
+            next_peer: 0,
+            rng: 0x9e3779b97f4a7c15 ^ (cluster.index() as u64 + 1),
+            deltas: VecDeque::new(),
+            // Peers start at 0, so the first round sends the initial state
+            // whole.
+            base_seq: 1,
+            next_seq: 1,
+            acked: HashMap::new(),
+            unacked: HashMap::new(),
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Changes the state without producing a delta, e.g. when restoring it
+    /// from disk. Peers only learn about this from full states.
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    /// Applies a mutation that returns its delta, like those in
+    /// [`crate::crdt`], queueing the delta for peers. Returns whether there
+    /// was one.
+    pub fn update<D: Into<Option<S>>>(&mut self, mutate: impl FnOnce(&mut S) -> D) -> bool {
+        match mutate(&mut self.state).into() {
+            Some(delta) => {
+                if self.config.mode == Mode::Delta {
+                    self.buffer(None, delta);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    /// Replaces who we gossip with, e.g. after a topology change.
+    pub fn set_peers(&mut self, peers: Vec<String>) {
+        self.peers = peers;
+        self.next_peer = 0;
+    }
+
+    /// Turns relaying on or off, for when a topology change connects every
+    /// node to every other or stops doing so. See [`Config::relay`].
+    pub fn set_relay(&mut self, relay: bool) {
+        self.config.relay = relay;
+    }
+
+    /// Runs a round if one is due.
+    pub fn tick(&mut self, now: Instant) -> Vec<(String, GossipMessage<S>)> {
+        if now.duration_since(self.last_round) < self.config.interval {
+            return Vec::new();
+        }
+        self.last_round = now;
+
+        let mut messages: Vec<(String, GossipMessage<S>)> = self
+            .select()
+            .into_iter()
+            .filter_map(|peer| {
+                let message = match self.config.mode {
+                    Mode::Push => GossipMessage::Push(self.body()),
+                    Mode::Pull => GossipMessage::Pull,
+                    Mode::PushPull => GossipMessage::PushPull(self.body()),
+                    Mode::Delta => {
+                        let mut body = self.missing(&peer)?;
+                        body.ack = self.unacked.remove(&peer);
+                        GossipMessage::Delta(body)
+                    }
+                };
+                Some((peer, message))
+            })
+            .collect();
+
+        let mut acks: Vec<(String, u64)> = self.unacked.drain().collect();
+        acks.sort();
+        messages.extend(
+            acks.into_iter()
+                .map(|(peer, seq)| (peer, GossipMessage::Ack(AckBody { seq }))),
+        );
+
+        messages
+    }
+
+    pub fn on_message(
+        &mut self,
+        from: &str,
+        message: GossipMessage<S>,
+    ) -> Vec<(String, GossipMessage<S>)> {
+        match message {
+            GossipMessage::Push(body) => {
+                self.state.merge(body.state);
+                Vec::new()
+            }
+            GossipMessage::Pull => vec![(from.to_string(), GossipMessage::Push(self.body()))],
+            GossipMessage::PushPull(body) => {
+                self.state.merge(body.state);
+                vec![(from.to_string(), GossipMessage::Push(self.body()))]
+            }
+            GossipMessage::Delta(body) => {
+                if let Some(new) = self.state.merge(body.state)
+                    && self.config.relay
+                {
+                    self.buffer(Some(from.to_string()), new);
+                }
+
+                let unacked = self.unacked.entry(from.to_string()).or_default();
+                *unacked = (*unacked).max(body.seq);
+                if let Some(seq) = body.ack {
+                    self.acknowledged(from, seq);
+                }
+                Vec::new()
+            }
+            GossipMessage::Ack(body) => {
+                self.acknowledged(from, body.seq);
+                Vec::new()
+            }
+        }
+    }
+
+    fn acknowledged(&mut self, peer: &str, seq: u64) {
+        let acked = self.acked.entry(peer.to_string()).or_default();
+        *acked = (*acked).max(seq);
+        self.collect_garbage();
+    }
+
+    /// What `peer` has not acknowledged yet, or `None` if it is up to date.
+    fn missing(&mut self, peer: &str) -> Option<DeltaBody<S>> {
+        let acked = self.acked.get(peer).copied().unwrap_or_default();
+        if acked >= self.next_seq {
+            return None;
+        }
+
+        if acked < self.base_seq {
+            return Some(DeltaBody {
+                state: self.state.clone(),
+                seq: self.next_seq,
+                ack: None,
+            });
+        }
+
+        let mut state = S::default();
+        let mut empty = true;
+        for (_, _, delta) in self
+            .deltas
+            .iter()
+            .filter(|(seq, origin, _)| *seq >= acked && origin.as_deref() != Some(peer))
+        {
+            state.merge(delta.clone());
+            empty = false;
+        }
+
+        if empty {
+            // Everything since came from the peer itself.
+            self.acked.insert(peer.to_string(), self.next_seq);
+            return None;
+        }
+
+        Some(DeltaBody {
+            state,
+            seq: self.next_seq,
+            ack: None,
+        })
+    }
+
+    fn buffer(&mut self, origin: Option<String>, delta: S) {
+        self.deltas.push_back((self.next_seq, origin, delta));
+        self.next_seq += 1;
+
+        while self.deltas.len() > self.config.max_deltas {
+            let (seq, ..) = self.deltas.pop_front().unwrap();
+            self.base_seq = seq + 1;
+        }
+    }
+
+    /// Drops the deltas every peer has acknowledged.
+    fn collect_garbage(&mut self) {
+        let oldest = self
+            .peers
+            .iter()
+            .map(|peer| self.acked.get(peer).copied().unwrap_or_default())
+            .min()
+            .unwrap_or(self.next_seq);
+
+        while self.deltas.front().is_some_and(|(seq, ..)| *seq < oldest) {
+            let (seq, ..) = self.deltas.pop_front().unwrap();
+            self.base_seq = seq + 1;
+        }
+    }
+
+    fn body(&self) -> GossipBody<S> {
+        GossipBody {
+            state: self.state.clone(),
+        }
+    }
+
+    fn select(&mut self) -> Vec<String> {
+        let n = self.peers.len();
+        if self.config.fanout >= n {
+            return self.peers.clone();
+        }
+
+        match self.config.selection {
+            PeerSelection::RoundRobin => {
+                let start = self.next_peer % n;
+                self.next_peer = (start + self.config.fanout) % n;
+                (start..start + self.config.fanout)
+                    .map(|i| self.peers[i % n].clone())
+                    .collect()
+            }
+            PeerSelection::Random => {
+                // A partial Fisher-Yates shuffle of the peer indices.
+                let mut indices: Vec<usize> = (0..n).collect();
+                for i in 0..self.config.fanout {
+                    let j = i + (self.next_random() % (n - i) as u64) as usize;
+                    indices.swap(i, j);
+                }
+                indices[..self.config.fanout]
+                    .iter()
+                    .map(|&i| self.peers[i].clone())
+                    .collect()
+            }
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*, seeded per node so peers pick differently.
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        InitBody,
+        crdt::GSet,
+        sim::{Network, Peer},
+    };
+    use std::collections::HashSet;
+
+    impl<S: Merge + Clone + Default + PartialEq> Peer for Gossip<S> {
+        type Message = GossipMessage<S>;
+
+        fn tick(&mut self, now: Instant) -> Vec<(String, Self::Message)> {
+            Gossip::tick(self, now)
+        }
+
+        fn receive(
+            &mut self,
+            from: &str,
+            message: Self::Message,
+            _now: Instant,
+        ) -> Vec<(String, Self::Message)> {
+            self.on_message(from, message)
+        }
+    }
+
+    fn network(n: usize, config: Config) -> Network<Gossip<GSet<u64>>> {
+        let now = Instant::now();
+        let node_ids: Vec<String> = (1..=n).map(|i| format!("n{i}")).collect();
+
+        Network::new(
+            now,
+            (1..=n).map(|i| {
+                let cluster = Cluster::from(InitBody {
+                    node_id: format!("n{i}"),
+                    node_ids: node_ids.clone(),
+                });
+                let peers = cluster.peers().cloned().collect();
+                let state = GSet::from(HashSet::from([i as u64]));
+                (
+                    format!("n{i}"),
+                    Gossip::new(&cluster, peers, state, config, now),
+                )
+            }),
+        )
+    }
+
+    fn converged(network: &Network<Gossip<GSet<u64>>>) -> bool {
+        let all: HashSet<u64> = (1..=network.nodes.len() as u64).collect();
+        network
+            .nodes
+            .values()
+            .all(|node| node.state().elements() == &all)
+    }
+
+    #[test]
+    fn test_every_mode_converges() {
+        for mode in [Mode::Push, Mode::Pull, Mode::PushPull, Mode::Delta] {
+            let mut network = network(
+                5,
+                Config {
+                    interval: Duration::from_millis(50),
+                    fanout: 2,
+                    mode,
+                    ..Config::default()
+                },
+            );
+
+            network.run(Duration::from_secs(1), |_, _| {});
+            assert!(converged(&network), "{mode:?} did not converge");
+        }
+    }
+
+    #[test]
+    fn test_converges_after_partition() {
+        let mut network = network(
+            4,
+            Config {
+                interval: Duration::from_millis(50),
+                ..Config::default()
+            },
+        );
+
+        network.partition(&[&["n1", "n2"], &["n3", "n4"]]);
+        network.run(Duration::from_millis(500), |_, _| {});
+        assert!(!converged(&network));
+        assert_eq!(
+            network.nodes["n1"].state().elements(),
+            &HashSet::from([1, 2])
+        );
+
+        network.heal();
+        network.run(Duration::from_millis(500), |_, _| {});
+        assert!(converged(&network));
+    }
+
+    #[test]
+    fn test_delta_sends_only_what_is_missing() {
+        let interval = Duration::from_millis(50);
+        let mut network = network(
+            3,
+            Config {
+                interval,
+                mode: Mode::Delta,
+                ..Config::default()
+            },
+        );
+
+        network.run(Duration::from_millis(500), |_, _| {});
+        assert!(converged(&network));
+
+        let (idle, delta) = network.with("n1", |node, now| {
+            let idle = node.tick(now + interval);
+            assert!(node.update(|s| s.insert(4)));
+            assert!(!node.update(|s| s.insert(4)));
+            let delta = node.tick(now + interval * 2);
+            ((idle, delta), Vec::new())
+        });
+        assert!(idle.is_empty());
+        assert_eq!(delta.len(), 2);
+        for (_, message) in delta {
+            let GossipMessage::Delta(body) = message else {
+                panic!("expected a delta, got {message:?}");
+            };
+            assert_eq!(body.state, GSet::from(HashSet::from([4])));
+        }
+    }
+
+    #[test]
+    fn test_delta_falls_back_to_full_state() {
+        let mut network = network(
+            3,
+            Config {
+                interval: Duration::from_millis(50),
+                mode: Mode::Delta,
+                max_deltas: 2,
+                ..Config::default()
+            },
+        );
+        network.run(Duration::from_millis(500), |_, _| {});
+
+        network.partition(&[&["n1"], &["n2", "n3"]]);
+        for value in 10..15 {
+            network
+                .nodes
+                .get_mut("n1")
+                .unwrap()
+                .update(|s| s.insert(value));
+        }
+        network.run(Duration::from_millis(500), |_, _| {});
+
+        // n1 has dropped deltas n2 never acknowledged, so n2 gets it all.
+        let n1 = network.nodes.get_mut("n1").unwrap();
+        assert_eq!(n1.deltas.len(), 2);
+        assert_eq!(n1.missing("n2").unwrap().state, n1.state);
+
+        network.heal();
+        network.run(Duration::from_millis(500), |_, _| {});
+
+        let all: HashSet<u64> = (1..=3).chain(10..15).collect();
+        for node in network.nodes.values() {
+            assert_eq!(node.state().elements(), &all);
+            assert!(node.deltas.len() <= 2);
+        }
+    }
+
+    fn delta_node(node_id: &str, peers: &[&str], relay: bool) -> Gossip<GSet<u64>> {
+        let cluster = Cluster::from(InitBody {
+            node_id: node_id.to_string(),
+            node_ids: (1..=3).map(|i| format!("n{i}")).collect(),
+        });
+        Gossip::new(
+            &cluster,
+            peers.iter().map(|p| p.to_string()).collect(),
+            GSet::default(),
+            Config {
+                interval: Duration::ZERO,
+                mode: Mode::Delta,
+                relay,
+                ..Config::default()
+            },
+            Instant::now(),
+        )
+    }
+
+    #[test]
+    fn test_delta_acks_ride_on_deltas() {
+        let now = Instant::now();
+        let mut n1 = delta_node("n1", &["n2"], false);
+        let mut n2 = delta_node("n2", &["n1"], false);
+        n1.update(|s| s.insert(1));
+        n2.update(|s| s.insert(2));
+
+        let [(_, to_n2)] = <[_; 1]>::try_from(n1.tick(now)).unwrap();
+        assert!(n2.on_message("n1", to_n2).is_empty());
+
+        // n2's own delta carries the ack, so there is no separate one.
+        let [(_, to_n1)] = <[_; 1]>::try_from(n2.tick(now)).unwrap();
+        let GossipMessage::Delta(body) = &to_n1 else {
+            panic!("expected a delta, got {to_n1:?}");
+        };
+        assert_eq!(body.ack, Some(2));
+        assert!(n1.on_message("n2", to_n1).is_empty());
+
+        // n1 has nothing new for n2, so its ack goes alone, once.
+        assert_eq!(
+            n1.tick(now),
+            [("n2".to_string(), GossipMessage::Ack(AckBody { seq: 2 }))]
+        );
+        assert!(n1.tick(now).is_empty());
+        assert!(n1.deltas.is_empty());
+    }
+
+    #[test]
+    fn test_delta_relays_only_what_is_new() {
+        let now = Instant::now();
+        let received = |relay| {
+            let mut n1 = delta_node("n1", &["n2", "n3"], relay);
+            n1.update(|s| s.insert(1));
+            n1.on_message("n3", GossipMessage::Ack(AckBody { seq: 2 }));
+
+            n1.on_message(
+                "n2",
+                GossipMessage::Delta(DeltaBody {
+                    state: GSet::from(HashSet::from([1, 2])),
+                    seq: 1,
+                    ack: None,
+                }),
+            );
+            n1.tick(now)
+                .into_iter()
+                .filter(|(peer, _)| peer == "n3")
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            received(true),
+            [(
+                "n3".to_string(),
+                GossipMessage::Delta(DeltaBody {
+                    state: GSet::from(HashSet::from([2])),
+                    seq: 3,
+                    ack: None,
+                })
+            )]
+        );
+        assert!(received(false).is_empty());
+    }
+
+    #[test]
+    fn test_round_robin_reaches_every_peer() {
+        let cluster = Cluster::from(InitBody {
+            node_id: "n1".to_string(),
+            node_ids: (1..=5).map(|i| format!("n{i}")).collect(),
+        });
+        let now = Instant::now();
+        let interval = Duration::from_millis(10);
+        let mut gossip = Gossip::new(
+            &cluster,
+            cluster.peers().cloned().collect(),
+            GSet::<u64>::default(),
+            Config {
+                interval,
+                fanout: 3,
+                selection: PeerSelection::RoundRobin,
+                ..Config::default()
+            },
+            now,
+        );
+
+        assert!(gossip.tick(now).is_empty());
+
+        let rounds: Vec<Vec<String>> = (1..=2)
+            .map(|i| {
+                gossip
+                    .tick(now + interval * i)
+                    .into_iter()
+                    .map(|(peer, _)| peer)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rounds, [["n2", "n3", "n4"], ["n5", "n2", "n3"]]);
+    }
+
+    #[test]
+    fn test_random_selection_picks_distinct_peers() {
+        let cluster = Cluster::from(InitBody {
+            node_id: "n1".to_string(),
+            node_ids: (1..=10).map(|i| format!("n{i}")).collect(),
+        });
+        let now = Instant::now();
+        let interval = Duration::from_millis(10);
+        let mut gossip = Gossip::new(
+            &cluster,
+            cluster.peers().cloned().collect(),
+            GSet::<u64>::default(),
+            Config {
+                interval,
+                fanout: 4,
+                mode: Mode::Pull,
+                ..Config::default()
+            },
+            now,
+        );
+
+        let mut seen = HashSet::new();
+        for i in 1..=20 {
+            let round: HashSet<String> = gossip
+                .tick(now + interval * i)
+                .into_iter()
+                .map(|(peer, message)| {
+                    assert_eq!(message, GossipMessage::Pull);
+                    peer
+                })
+                .collect();
+            assert_eq!(round.len(), 4);
+            seen.extend(round);
+        }
+        assert_eq!(seen.len(), 9);
+    }
+
+    #[test]
+    fn test_pull_answers_with_state() {
+        let cluster = Cluster::from(InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        });
+        let mut gossip = Gossip::new(
+            &cluster,
+            vec!["n2".to_string()],
+            GSet::from(HashSet::from([1])),
+            Config::default(),
+            Instant::now(),
+        );
+
+        assert!(
+            gossip
+                .on_message(
+                    "n2",
+                    GossipMessage::Push(GossipBody {
+                        state: GSet::from(HashSet::from([2]))
+                    })
+                )
+                .is_empty()
+        );
+        assert_eq!(
+            gossip.on_message("n2", GossipMessage::Pull),
+            [(
+                "n2".to_string(),
+                GossipMessage::Push(GossipBody {
+                    state: GSet::from(HashSet::from([1, 2]))
+                })
+            )]
+        );
+    }
+}
@@ -1,13 +1,69 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fmt,
+    marker::PhantomData,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
-use crate::{Message, MessageBody};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
-#[derive(Debug, PartialEq, Serialize)]
+use crate::{
+    Message, MessageBody, MessageID, MessageIds, Response,
+    cluster::{self, Cluster},
+};
+
+/// Which of Maelstrom's key-value services a [`Client`] talks to, and so
+/// what it guarantees.
+pub trait Consistency {
+    const SERVICE: &'static str;
+    /// Whether a read may be answered with a value the node saw earlier
+    /// rather than asking the service again.
+    const STALE_READS: bool;
+}
+
+/// Sequentially consistent: every node sees writes in the same order, but
+/// reads may be stale.
+pub struct SeqKv;
+
+/// Linearizable: reads see every write that completed before they began.
+pub struct LinKv;
+
+/// Last write wins: concurrent writes to a key may be lost, and reads may be
+/// stale.
+pub struct LwwKv;
+
+impl Consistency for SeqKv {
+    const SERVICE: &'static str = "seq-kv";
+    const STALE_READS: bool = true;
+}
+
+impl Consistency for LinKv {
+    const SERVICE: &'static str = "lin-kv";
+    const STALE_READS: bool = false;
+}
+
+impl Consistency for LwwKv {
+    const SERVICE: &'static str = "lww-kv";
+    const STALE_READS: bool = true;
+}
+
+/// Every key-value service, as it appears in `src` of its replies.
+pub const SERVICES: [&str; 3] = [SeqKv::SERVICE, LinKv::SERVICE, LwwKv::SERVICE];
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct ReadBody {
     key: String,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct WriteBody<T> {
+    key: String,
+    value: T,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct CompareAndSwapBody<T> {
     key: String,
     from: T,
@@ -15,70 +71,1488 @@ pub struct CompareAndSwapBody<T> {
     create_if_not_exists: bool,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum MessageType<T> {
     Read(ReadBody),
-    Write,
+    Write(WriteBody<T>),
     #[serde(rename = "cas")]
     CompareAndSwap(CompareAndSwapBody<T>),
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct ReadOkBody {
     pub value: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+/// An `error` from the service; its `in_reply_to` is on the enclosing
+/// [`crate::ResponseBody`].
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ErrorBody {
+    pub code: u32,
+    #[serde(default)]
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ResponseType {
     ReadOk(ReadOkBody),
     WriteOk,
     #[serde(rename = "cas_ok")]
     CompareAndSwapOk,
+    Error(ErrorBody),
+}
+
+/// Why a key-value operation failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KvError {
+    KeyDoesNotExist,
+    /// A compare-and-swap found something other than `from`.
+    PreconditionFailed,
+    /// No answer within the client's timeout, or the service gave up itself.
+    Timeout,
+    /// The value is not the type the caller asked for.
+    Decode(String),
+    Other {
+        code: u32,
+        text: String,
+    },
 }
 
-pub struct Sequential {
+impl From<ErrorBody> for KvError {
+    fn from(error: ErrorBody) -> Self {
+        match error.code {
+            crate::ErrorBody::TIMEOUT => KvError::Timeout,
+            crate::ErrorBody::KEY_DOES_NOT_EXIST => KvError::KeyDoesNotExist,
+            crate::ErrorBody::PRECONDITION_FAILED => KvError::PreconditionFailed,
+            code => KvError::Other {
+                code,
+                text: error.text,
+            },
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist => write!(f, "key does not exist"),
+            KvError::PreconditionFailed => write!(f, "precondition failed"),
+            KvError::Timeout => write!(f, "timed out"),
+            KvError::Decode(reason) => write!(f, "could not decode value: {reason}"),
+            KvError::Other { code, text } => write!(f, "error {code}: {text}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/// What a successful operation came back with.
+#[derive(Debug, PartialEq)]
+pub enum Reply<T> {
+    Read(T),
+    Written,
+    Swapped,
+}
+
+/// A request the client has stopped waiting for, one way or another.
+#[derive(Debug, PartialEq)]
+pub struct Outcome<T> {
+    pub msg_id: MessageID,
+    pub key: String,
+    pub result: Result<Reply<T>, KvError>,
+}
+
+struct Pending {
+    key: String,
+    deadline: Instant,
+    /// What the key holds once a write or compare-and-swap succeeds, kept
+    /// only when caching.
+    written: Option<serde_json::Value>,
+}
+
+/// What the client last learned about a key, and from which request.
+struct Cached {
+    /// Replies to requests sent before this one are older news and ignored.
+    msg_id: MessageID,
+    /// `None` once the value is known to have changed, or is missing.
+    value: Option<serde_json::Value>,
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Builds requests for a key-value service and matches its replies back to
+/// them by `msg_id`.
+pub struct Client<C> {
     node_id: String,
+    ids: MessageIds,
+    timeout: Duration,
+    pending: HashMap<MessageID, Pending>,
+    /// The last value seen for each key, if caching.
+    cache: Option<HashMap<String, Cached>>,
+    consistency: PhantomData<C>,
 }
 
-impl Sequential {
-    pub fn new(node_id: String) -> Self {
-        Self { node_id }
+pub type Sequential = Client<SeqKv>;
+
+pub type Linearizable = Client<LinKv>;
+
+pub type LastWriteWins = Client<LwwKv>;
+
+impl<C: Consistency> Client<C> {
+    /// Numbers requests from `ids`, which should be shared with everything
+    /// else the node sends.
+    pub fn new(node_id: String, ids: MessageIds) -> Self {
+        Self {
+            node_id,
+            ids,
+            timeout: DEFAULT_TIMEOUT,
+            pending: HashMap::new(),
+            cache: None,
+            consistency: PhantomData,
+        }
+    }
+
+    /// Remembers the last value read or written for each key, so
+    /// [`Client::cached`] can answer reads without a round trip.
+    pub fn with_cache(mut self) -> Self {
+        self.cache = Some(HashMap::new());
+        self
+    }
+
+    /// The last value seen for `key`, if caching and the service's
+    /// consistency allows a read to be that stale. Check this before
+    /// [`Client::read`].
+    ///
+    /// A successful compare-and-swap from the cached value proves it was
+    /// current; a failed one drops it, so the next read goes to the service.
+    pub fn cached<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        if !C::STALE_READS {
+            return None;
+        }
+
+        let value = self.cache.as_ref()?.get(key)?.value.as_ref()?;
+        serde_json::from_value(value.clone()).ok()
+    }
+
+    /// Forgets the cached value for `key`, and ignores replies to requests
+    /// already in flight, which may predate whatever made it doubtful.
+    pub fn invalidate(&mut self, key: &str) {
+        let newest = self.pending.keys().max().copied().unwrap_or_default();
+        if let Some(cache) = &mut self.cache {
+            let cached = cache.entry(key.to_string()).or_insert(Cached {
+                msg_id: newest,
+                value: None,
+            });
+            cached.msg_id = cached.msg_id.max(newest);
+            cached.value = None;
+        }
+    }
+
+    /// How long to wait for a reply before [`Client::expire`] gives up on it.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn read<T: Serialize>(&mut self, key: &str) -> Message<MessageType<T>> {
+        self.request(MessageType::Read(ReadBody {
+            key: key.to_string(),
+        }))
+    }
+
+    /// Sets `key` to `value`, whatever it held before.
+    pub fn write<T: Serialize>(&mut self, key: &str, value: T) -> Message<MessageType<T>> {
+        self.request(MessageType::Write(WriteBody {
+            key: key.to_string(),
+            value,
+        }))
+    }
+
+    pub fn compare_and_swap<T: Serialize>(
+        &mut self,
+        key: &str,
+        from: T,
+        to: T,
+        create_if_not_exists: bool,
+    ) -> Message<MessageType<T>> {
+        self.request(MessageType::CompareAndSwap(CompareAndSwapBody {
+            key: key.to_string(),
+            from,
+            to,
+            create_if_not_exists,
+        }))
+    }
+
+    /// Matches a reply to its request, decoding a read value as `T`. `None`
+    /// for replies to requests that were never sent or have already expired.
+    pub fn resolve<T: DeserializeOwned>(
+        &mut self,
+        response: Response<ResponseType>,
+    ) -> Option<Outcome<T>> {
+        let msg_id = response.body.in_reply_to?;
+        let pending = self.pending.remove(&msg_id)?;
+
+        let result = match response.body.kind {
+            ResponseType::ReadOk(body) => {
+                self.remember(&pending.key, msg_id, body.value.clone());
+                serde_json::from_value(body.value.unwrap_or_default())
+                    .map(Reply::Read)
+                    .map_err(|e| KvError::Decode(e.to_string()))
+            }
+            ResponseType::WriteOk => Ok(Reply::Written),
+            ResponseType::CompareAndSwapOk => Ok(Reply::Swapped),
+            ResponseType::Error(error) => Err(error.into()),
+        };
+
+        match (&result, pending.written) {
+            (Ok(_), Some(written)) => self.remember(&pending.key, msg_id, Some(written)),
+            // The key holds something other than we thought, or nothing.
+            (Err(KvError::PreconditionFailed | KvError::KeyDoesNotExist), _) => {
+                self.remember(&pending.key, msg_id, None)
+            }
+            _ => {}
+        }
+
+        Some(Outcome {
+            msg_id,
+            key: pending.key,
+            result,
+        })
+    }
+
+    /// Gives up on every request that has gone unanswered past the timeout.
+    pub fn expire<T>(&mut self, now: Instant) -> Vec<Outcome<T>> {
+        let mut expired: Vec<MessageID> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(&msg_id, _)| msg_id)
+            .collect();
+        expired.sort_unstable();
+
+        expired
+            .into_iter()
+            .filter_map(|msg_id| {
+                let pending = self.pending.remove(&msg_id)?;
+                // A write that timed out may or may not have happened.
+                if pending.written.is_some() {
+                    self.remember(&pending.key, msg_id, None);
+                }
+                Some(Outcome {
+                    msg_id,
+                    key: pending.key,
+                    result: Err(KvError::Timeout),
+                })
+            })
+            .collect()
+    }
+
+    /// Caches what request `msg_id` learned about `key`, unless a later
+    /// request has already told us more.
+    fn remember(&mut self, key: &str, msg_id: MessageID, value: Option<serde_json::Value>) {
+        let Some(cache) = &mut self.cache else {
+            return;
+        };
+
+        if cache.get(key).is_none_or(|cached| cached.msg_id < msg_id) {
+            cache.insert(key.to_string(), Cached { msg_id, value });
+        }
     }
 
-    pub fn read<T>(&self, key: &str) -> Message<MessageType<T>> {
-        Message::<MessageType<T>> {
+    fn request<T: Serialize>(&mut self, kind: MessageType<T>) -> Message<MessageType<T>> {
+        let msg_id = self.ids.next();
+
+        let (key, written) = match &kind {
+            MessageType::Read(body) => (&body.key, None),
+            MessageType::Write(body) => (&body.key, Some(&body.value)),
+            MessageType::CompareAndSwap(body) => (&body.key, Some(&body.to)),
+        };
+        let written = match (&self.cache, written) {
+            (Some(_), Some(value)) => serde_json::to_value(value).ok(),
+            _ => None,
+        };
+        self.pending.insert(
+            msg_id,
+            Pending {
+                key: key.clone(),
+                deadline: Instant::now() + self.timeout,
+                written,
+            },
+        );
+
+        Message {
             src: self.node_id.clone(),
-            dst: "seq-kv".to_string(),
-            body: MessageBody::<MessageType<T>> {
-                kind: MessageType::Read(ReadBody {
-                    key: key.to_string(),
-                }),
-                msg_id: None,
+            dst: C::SERVICE.to_string(),
+            body: MessageBody {
+                kind,
+                msg_id: Some(msg_id),
             },
         }
     }
+}
+
+/// What the helpers in this module, and nodes built on them, need from a
+/// key-value client: [`Client`] against Maelstrom, or [`Memory`] in tests.
+pub trait KvClient {
+    type Consistency: Consistency;
+
+    fn read<T: Serialize>(&mut self, key: &str) -> Message<MessageType<T>>;
+
+    fn write<T: Serialize>(&mut self, key: &str, value: T) -> Message<MessageType<T>>;
+
+    fn compare_and_swap<T: Serialize>(
+        &mut self,
+        key: &str,
+        from: T,
+        to: T,
+        create_if_not_exists: bool,
+    ) -> Message<MessageType<T>>;
+
+    fn resolve<T: DeserializeOwned>(
+        &mut self,
+        response: Response<ResponseType>,
+    ) -> Option<Outcome<T>>;
+
+    fn expire<T>(&mut self, now: Instant) -> Vec<Outcome<T>>;
+}
+
+impl<C: Consistency> KvClient for Client<C> {
+    type Consistency = C;
+
+    fn read<T: Serialize>(&mut self, key: &str) -> Message<MessageType<T>> {
+        Client::read(self, key)
+    }
+
+    fn write<T: Serialize>(&mut self, key: &str, value: T) -> Message<MessageType<T>> {
+        Client::write(self, key, value)
+    }
+
+    fn compare_and_swap<T: Serialize>(
+        &mut self,
+        key: &str,
+        from: T,
+        to: T,
+        create_if_not_exists: bool,
+    ) -> Message<MessageType<T>> {
+        Client::compare_and_swap(self, key, from, to, create_if_not_exists)
+    }
+
+    fn resolve<T: DeserializeOwned>(
+        &mut self,
+        response: Response<ResponseType>,
+    ) -> Option<Outcome<T>> {
+        Client::resolve(self, response)
+    }
+
+    fn expire<T>(&mut self, now: Instant) -> Vec<Outcome<T>> {
+        Client::expire(self, now)
+    }
+}
+
+/// How hard [`Updates`] tries before giving up on a contended key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retry {
+    /// Compare-and-swaps to attempt, including the first.
+    pub max_attempts: u32,
+    /// The wait before the first retry, doubling for each one after.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
 
-    pub fn compare_and_swap<T>(
+pub type UpdateId = u64;
+
+/// A finished [`Updates::update`]: the value written, or why it gave up.
+#[derive(Debug, PartialEq)]
+pub struct Updated<T> {
+    pub id: UpdateId,
+    pub key: String,
+    pub result: Result<T, KvError>,
+}
+
+/// Where an update is after one of its replies.
+#[derive(Debug, PartialEq)]
+pub enum Progress<T> {
+    Send(Message<MessageType<T>>),
+    /// Lost a race; it re-reads on a later [`Updates::tick`].
+    Backoff,
+    Done(Updated<T>),
+}
+
+enum Step<T> {
+    Reading,
+    Swapping(T),
+    Waiting(Instant),
+}
+
+struct Update<T> {
+    key: String,
+    default: T,
+    f: Box<dyn FnMut(&T) -> T>,
+    attempts: u32,
+    step: Step<T>,
+}
+
+/// Read-modify-write on top of [`Client::read`] and
+/// [`Client::compare_and_swap`]: read the key, apply `f`, swap the result in
+/// if nobody else got there first, and otherwise back off and start over.
+pub struct Updates<T> {
+    retry: Retry,
+    next_id: UpdateId,
+    running: HashMap<UpdateId, Update<T>>,
+    requests: HashMap<MessageID, UpdateId>,
+}
+
+impl<T: Clone + Serialize> Updates<T> {
+    pub fn new(retry: Retry) -> Self {
+        Self {
+            retry,
+            next_id: 1,
+            running: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Starts updating `key` to `f` of its value, or of `default` if it has
+    /// none yet. `f` may run several times, once per attempt.
+    pub fn update<K: KvClient>(
+        &mut self,
+        client: &mut K,
+        key: &str,
+        default: T,
+        f: impl FnMut(&T) -> T + 'static,
+    ) -> (UpdateId, Message<MessageType<T>>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.running.insert(
+            id,
+            Update {
+                key: key.to_string(),
+                default,
+                f: Box::new(f),
+                attempts: 0,
+                step: Step::Reading,
+            },
+        );
+
+        (id, self.read(client, id))
+    }
+
+    /// Whether `msg_id` is a request one of the updates is waiting on.
+    pub fn owns(&self, msg_id: MessageID) -> bool {
+        self.requests.contains_key(&msg_id)
+    }
+
+    /// Takes the next step for the update `outcome` belongs to. `None` if it
+    /// belongs to none.
+    pub fn on_outcome<K: KvClient>(
+        &mut self,
+        client: &mut K,
+        outcome: Outcome<T>,
+        now: Instant,
+    ) -> Option<Progress<T>> {
+        let id = self.requests.remove(&outcome.msg_id)?;
+        let update = self.running.get_mut(&id)?;
+
+        let step = std::mem::replace(&mut update.step, Step::Reading);
+        match (step, outcome.result) {
+            (Step::Reading, Ok(Reply::Read(current))) => {
+                let to = (update.f)(&current);
+                Some(Progress::Send(self.swap(client, id, current, to, false)))
+            }
+            (Step::Reading, Err(KvError::KeyDoesNotExist)) => {
+                let from = update.default.clone();
+                let to = (update.f)(&from);
+                Some(Progress::Send(self.swap(client, id, from, to, true)))
+            }
+            // A read has no effect, so a lost one is just another attempt.
+            (Step::Reading, Err(KvError::Timeout)) => {
+                Some(self.back_off(id, KvError::Timeout, now))
+            }
+            (Step::Swapping(to), Ok(Reply::Swapped)) => Some(self.finish(id, Ok(to))),
+            (Step::Swapping(_), Err(KvError::PreconditionFailed | KvError::KeyDoesNotExist)) => {
+                Some(self.back_off(id, KvError::PreconditionFailed, now))
+            }
+            // Anything else, including a swap that timed out and so may or
+            // may not have happened, is for the caller to sort out.
+            (_, Err(error)) => Some(self.finish(id, Err(error))),
+            (_, Ok(_)) => Some(self.finish(
+                id,
+                Err(KvError::Decode("reply does not match request".to_string())),
+            )),
+        }
+    }
+
+    /// Re-reads for every update whose backoff is over.
+    pub fn tick<K: KvClient>(
+        &mut self,
+        client: &mut K,
+        now: Instant,
+    ) -> Vec<Message<MessageType<T>>> {
+        let mut due: Vec<UpdateId> = self
+            .running
+            .iter()
+            .filter(|(_, update)| matches!(update.step, Step::Waiting(at) if at <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        due.sort_unstable();
+
+        due.into_iter().map(|id| self.read(client, id)).collect()
+    }
+
+    fn read<K: KvClient>(&mut self, client: &mut K, id: UpdateId) -> Message<MessageType<T>> {
+        let update = self.running.get_mut(&id).expect("update is running");
+        update.step = Step::Reading;
+
+        let message = client.read(&update.key);
+        self.requests
+            .insert(message.body.msg_id.expect("client sets msg_id"), id);
+        message
+    }
+
+    fn swap<K: KvClient>(
+        &mut self,
+        client: &mut K,
+        id: UpdateId,
+        from: T,
+        to: T,
+        create_if_not_exists: bool,
+    ) -> Message<MessageType<T>> {
+        let update = self.running.get_mut(&id).expect("update is running");
+        update.step = Step::Swapping(to.clone());
+
+        let message = client.compare_and_swap(&update.key, from, to, create_if_not_exists);
+        self.requests
+            .insert(message.body.msg_id.expect("client sets msg_id"), id);
+        message
+    }
+
+    /// Schedules another attempt, or gives up with `error` if that was the
+    /// last.
+    fn back_off(&mut self, id: UpdateId, error: KvError, now: Instant) -> Progress<T> {
+        let update = self.running.get_mut(&id).expect("update is running");
+        update.attempts += 1;
+        if update.attempts >= self.retry.max_attempts {
+            return self.finish(id, Err(error));
+        }
+
+        let backoff = self
+            .retry
+            .backoff
+            .saturating_mul(1 << (update.attempts - 1).min(16))
+            .min(self.retry.max_backoff);
+        update.step = Step::Waiting(now + backoff);
+        Progress::Backoff
+    }
+
+    fn finish(&mut self, id: UpdateId, result: Result<T, KvError>) -> Progress<T> {
+        let update = self.running.remove(&id).expect("update is running");
+        Progress::Done(Updated {
+            id,
+            key: update.key,
+            result,
+        })
+    }
+}
+
+pub type ReadId = u64;
+
+/// A finished [`Sharded::read`]: every shard combined, or the first error.
+#[derive(Debug, PartialEq)]
+pub struct Gathered<T> {
+    pub id: ReadId,
+    pub result: Result<T, KvError>,
+}
+
+struct Gathering<T> {
+    value: T,
+    waiting: usize,
+}
+
+/// One logical value spread over several physical keys, so writers that
+/// would all compare-and-swap one hot key mostly touch different ones.
+/// Reading it reads every shard and folds them together with `combine`.
+pub struct Sharded<T> {
+    keys: Vec<String>,
+    /// The writer each key belongs to, for one shard per node. Empty for
+    /// hashed buckets.
+    writers: Vec<String>,
+    empty: T,
+    combine: fn(T, T) -> T,
+    next_id: ReadId,
+    reads: HashMap<ReadId, Gathering<T>>,
+    requests: HashMap<MessageID, ReadId>,
+}
+
+impl<T: Clone + Serialize> Sharded<T> {
+    /// A shard per node, `<name>-<node id>`, each written only by its node,
+    /// so writes never contend across nodes.
+    pub fn per_node(name: &str, cluster: &Cluster, empty: T, combine: fn(T, T) -> T) -> Self {
+        let writers = cluster.node_ids().to_vec();
+        let keys = writers.iter().map(|n| format!("{name}-{n}")).collect();
+        Self::with_keys(keys, writers, empty, combine)
+    }
+
+    /// `n` shards, `<name>-0` to `<name>-<n - 1>`, with writers hashed
+    /// across them.
+    pub fn buckets(name: &str, n: usize, empty: T, combine: fn(T, T) -> T) -> Self {
+        assert!(n > 0, "a sharded value needs at least one shard");
+        let keys = (0..n).map(|i| format!("{name}-{i}")).collect();
+        Self::with_keys(keys, Vec::new(), empty, combine)
+    }
+
+    fn with_keys(
+        keys: Vec<String>,
+        writers: Vec<String>,
+        empty: T,
+        combine: fn(T, T) -> T,
+    ) -> Self {
+        Self {
+            keys,
+            writers,
+            empty,
+            combine,
+            next_id: 1,
+            reads: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    /// The key `writer` should update.
+    pub fn shard(&self, writer: &str) -> &str {
+        let i = match self.writers.iter().position(|w| w == writer) {
+            Some(i) => i,
+            None => (cluster::hash(writer.as_bytes()) % self.keys.len() as u64) as usize,
+        };
+        &self.keys[i]
+    }
+
+    /// Starts updating `writer`'s shard, an empty one if it has not been
+    /// written yet, to `f` of its value.
+    pub fn update<K: KvClient>(
         &self,
+        updates: &mut Updates<T>,
+        client: &mut K,
+        writer: &str,
+        f: impl FnMut(&T) -> T + 'static,
+    ) -> (UpdateId, Message<MessageType<T>>) {
+        updates.update(client, self.shard(writer), self.empty.clone(), f)
+    }
+
+    /// Starts reading every shard.
+    pub fn read<K: KvClient>(&mut self, client: &mut K) -> (ReadId, Vec<Message<MessageType<T>>>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let messages: Vec<_> = self.keys.iter().map(|key| client.read(key)).collect();
+        for message in &messages {
+            self.requests
+                .insert(message.body.msg_id.expect("client sets msg_id"), id);
+        }
+        self.reads.insert(
+            id,
+            Gathering {
+                value: self.empty.clone(),
+                waiting: messages.len(),
+            },
+        );
+
+        (id, messages)
+    }
+
+    /// Whether `msg_id` is a request one of the reads is waiting on.
+    pub fn owns(&self, msg_id: MessageID) -> bool {
+        self.requests.contains_key(&msg_id)
+    }
+
+    /// Folds in one shard. Returns the read once every shard is in, or as
+    /// soon as one fails.
+    pub fn on_outcome(&mut self, outcome: Outcome<T>) -> Option<Gathered<T>> {
+        let id = self.requests.remove(&outcome.msg_id)?;
+        let read = self.reads.get_mut(&id)?;
+
+        match outcome.result {
+            Ok(Reply::Read(value)) => {
+                let so_far = std::mem::replace(&mut read.value, self.empty.clone());
+                read.value = (self.combine)(so_far, value);
+            }
+            // Nobody has written that shard yet.
+            Err(KvError::KeyDoesNotExist) => {}
+            result => {
+                self.reads.remove(&id);
+                self.requests.retain(|_, read| *read != id);
+                let error = match result {
+                    Err(error) => error,
+                    Ok(_) => KvError::Decode("reply does not match request".to_string()),
+                };
+                return Some(Gathered {
+                    id,
+                    result: Err(error),
+                });
+            }
+        }
+
+        read.waiting -= 1;
+        if read.waiting > 0 {
+            return None;
+        }
+        let read = self.reads.remove(&id).expect("read is pending");
+        Some(Gathered {
+            id,
+            result: Ok(read.value),
+        })
+    }
+}
+
+/// The keys and values behind one or more [`Memory`] clients; clone it to
+/// give several nodes the same service.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore(Rc<RefCell<HashMap<String, serde_json::Value>>>);
+
+impl MemoryStore {
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.0.borrow().get(key).cloned()
+    }
+}
+
+/// A [`KvClient`] that answers every request as soon as it is made, from a
+/// [`MemoryStore`], so node logic can be tested without Maelstrom. The
+/// replies queue up until [`Memory::replies`] hands them over, in the order
+/// the requests were made.
+pub struct Memory<C> {
+    client: Client<C>,
+    store: MemoryStore,
+    /// What this client reads, if stale: the store as of its last write.
+    view: Option<HashMap<String, serde_json::Value>>,
+    cas_failures: usize,
+    replies: VecDeque<Response<ResponseType>>,
+}
+
+impl<C: Consistency> Memory<C> {
+    pub fn new(node_id: String, store: &MemoryStore) -> Self {
+        Self {
+            client: Client::new(node_id, MessageIds::default()),
+            store: store.clone(),
+            view: None,
+            cas_failures: 0,
+            replies: VecDeque::new(),
+        }
+    }
+
+    /// Serves reads from the store as it was when this client last wrote,
+    /// or when this was called, the way seq-kv lets a node fall behind
+    /// until it writes.
+    pub fn with_staleness(mut self) -> Self {
+        self.view = Some(self.store.0.borrow().clone());
+        self
+    }
+
+    /// Fails the next `n` compare-and-swaps with precondition-failed, as
+    /// if another node had got in first.
+    pub fn fail_cas(&mut self, n: usize) {
+        self.cas_failures = n;
+    }
+
+    /// Every reply not yet handed over.
+    pub fn replies(&mut self) -> Vec<Response<ResponseType>> {
+        self.replies.drain(..).collect()
+    }
+
+    fn serve<T: Serialize>(&mut self, message: &Message<MessageType<T>>) {
+        let error = |code| {
+            ResponseType::Error(ErrorBody {
+                code,
+                text: String::new(),
+            })
+        };
+        let json = |value: &T| serde_json::to_value(value).expect("values serialize as JSON");
+
+        let mut store = self.store.0.borrow_mut();
+        let kind = match &message.body.kind {
+            MessageType::Read(body) => match self.view.as_ref().unwrap_or(&store).get(&body.key) {
+                Some(value) => ResponseType::ReadOk(ReadOkBody {
+                    value: Some(value.clone()),
+                }),
+                None => error(crate::ErrorBody::KEY_DOES_NOT_EXIST),
+            },
+            MessageType::Write(body) => {
+                store.insert(body.key.clone(), json(&body.value));
+                ResponseType::WriteOk
+            }
+            MessageType::CompareAndSwap(_) if self.cas_failures > 0 => {
+                self.cas_failures -= 1;
+                error(crate::ErrorBody::PRECONDITION_FAILED)
+            }
+            MessageType::CompareAndSwap(body) => match store.get(&body.key) {
+                Some(current) if *current != json(&body.from) => {
+                    error(crate::ErrorBody::PRECONDITION_FAILED)
+                }
+                None if !body.create_if_not_exists => error(crate::ErrorBody::KEY_DOES_NOT_EXIST),
+                _ => {
+                    store.insert(body.key.clone(), json(&body.to));
+                    ResponseType::CompareAndSwapOk
+                }
+            },
+        };
+
+        // Writing brings a stale client up to date.
+        if matches!(kind, ResponseType::WriteOk | ResponseType::CompareAndSwapOk)
+            && let Some(view) = &mut self.view
+        {
+            view.clone_from(&store);
+        }
+
+        self.replies.push_back(Response {
+            src: C::SERVICE.to_string(),
+            dst: message.src.clone(),
+            body: crate::ResponseBody {
+                kind,
+                msg_id: None,
+                in_reply_to: message.body.msg_id,
+            },
+        });
+    }
+}
+
+impl<C: Consistency> KvClient for Memory<C> {
+    type Consistency = C;
+
+    fn read<T: Serialize>(&mut self, key: &str) -> Message<MessageType<T>> {
+        let message = self.client.read(key);
+        self.serve(&message);
+        message
+    }
+
+    fn write<T: Serialize>(&mut self, key: &str, value: T) -> Message<MessageType<T>> {
+        let message = self.client.write(key, value);
+        self.serve(&message);
+        message
+    }
+
+    fn compare_and_swap<T: Serialize>(
+        &mut self,
         key: &str,
         from: T,
         to: T,
         create_if_not_exists: bool,
     ) -> Message<MessageType<T>> {
-        Message::<MessageType<T>> {
-            src: self.node_id.clone(),
-            dst: "seq-kv".to_string(),
-            body: MessageBody::<MessageType<T>> {
-                kind: MessageType::CompareAndSwap(CompareAndSwapBody::<T> {
-                    key: key.to_string(),
-                    from,
-                    to,
-                    create_if_not_exists,
+        let message = self
+            .client
+            .compare_and_swap(key, from, to, create_if_not_exists);
+        self.serve(&message);
+        message
+    }
+
+    fn resolve<T: DeserializeOwned>(
+        &mut self,
+        response: Response<ResponseType>,
+    ) -> Option<Outcome<T>> {
+        self.client.resolve(response)
+    }
+
+    fn expire<T>(&mut self, now: Instant) -> Vec<Outcome<T>> {
+        self.client.expire(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_requests_match_maelstrom() {
+        let mut client = Sequential::new("n1".to_string(), MessageIds::default());
+
+        assert_eq!(
+            serde_json::to_value(client.read::<u64>("counter")).unwrap(),
+            json!({
+                "src": "n1",
+                "dest": "seq-kv",
+                "body": {"type": "read", "key": "counter", "msg_id": 1},
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(client.write("counter", 5)).unwrap(),
+            json!({
+                "src": "n1",
+                "dest": "seq-kv",
+                "body": {"type": "write", "key": "counter", "value": 5, "msg_id": 2},
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(client.compare_and_swap("counter", 5, 6, true)).unwrap(),
+            json!({
+                "src": "n1",
+                "dest": "seq-kv",
+                "body": {
+                    "type": "cas",
+                    "key": "counter",
+                    "from": 5,
+                    "to": 6,
+                    "create_if_not_exists": true,
+                    "msg_id": 3,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_clients_pick_service() {
+        assert_eq!(
+            Sequential::new("n1".to_string(), MessageIds::default())
+                .read::<u64>("k")
+                .dst,
+            "seq-kv"
+        );
+        assert_eq!(
+            Linearizable::new("n1".to_string(), MessageIds::default())
+                .read::<u64>("k")
+                .dst,
+            "lin-kv"
+        );
+        assert_eq!(
+            LastWriteWins::new("n1".to_string(), MessageIds::default())
+                .write("k", 1)
+                .dst,
+            "lww-kv"
+        );
+    }
+
+    #[test]
+    fn test_clients_share_message_ids() {
+        let ids = MessageIds::default();
+        let mut seq = Sequential::new("n1".to_string(), ids.clone());
+        let mut lin = Linearizable::new("n1".to_string(), ids.clone());
+
+        let sent = [
+            seq.read::<u64>("k").body.msg_id,
+            lin.read::<u64>("k").body.msg_id,
+            Some(ids.next()),
+            seq.write("k", 1).body.msg_id,
+        ];
+
+        assert_eq!(sent, [Some(1), Some(2), Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn test_responses_match_maelstrom() {
+        for (json, kind) in [
+            (
+                json!({"type": "read_ok", "value": 5, "in_reply_to": 1}),
+                ResponseType::ReadOk(ReadOkBody {
+                    value: Some(json!(5)),
+                }),
+            ),
+            (
+                json!({"type": "write_ok", "in_reply_to": 1}),
+                ResponseType::WriteOk,
+            ),
+            (
+                json!({"type": "cas_ok", "in_reply_to": 1}),
+                ResponseType::CompareAndSwapOk,
+            ),
+            (
+                json!({"type": "error", "code": 20, "text": "not found", "in_reply_to": 1}),
+                ResponseType::Error(ErrorBody {
+                    code: 20,
+                    text: "not found".to_string(),
                 }),
+            ),
+        ] {
+            let body: crate::ResponseBody<ResponseType> = serde_json::from_value(json).unwrap();
+            assert_eq!(body.kind, kind);
+            assert_eq!(body.in_reply_to, Some(1));
+        }
+    }
+
+    fn reply(in_reply_to: MessageID, kind: ResponseType) -> Response<ResponseType> {
+        Response {
+            src: "seq-kv".to_string(),
+            dst: "n1".to_string(),
+            body: crate::ResponseBody {
+                kind,
                 msg_id: None,
+                in_reply_to: Some(in_reply_to),
             },
         }
     }
+
+    fn error(code: u32) -> ResponseType {
+        ResponseType::Error(ErrorBody {
+            code,
+            text: String::new(),
+        })
+    }
+
+    #[test]
+    fn test_resolve_typed_results() {
+        let mut client = Sequential::new("n1".to_string(), MessageIds::default());
+        let read = client.read::<u64>("counter").body.msg_id.unwrap();
+        let missing = client.read::<u64>("other").body.msg_id.unwrap();
+        let cas = client
+            .compare_and_swap("counter", 1, 2, false)
+            .body
+            .msg_id
+            .unwrap();
+        let wrong_type = client.read::<u64>("name").body.msg_id.unwrap();
+
+        assert_eq!(
+            client.resolve::<u64>(reply(
+                read,
+                ResponseType::ReadOk(ReadOkBody {
+                    value: Some(json!(5)),
+                }),
+            )),
+            Some(Outcome {
+                msg_id: read,
+                key: "counter".to_string(),
+                result: Ok(Reply::Read(5)),
+            })
+        );
+        assert_eq!(
+            client
+                .resolve::<u64>(reply(missing, error(20)))
+                .unwrap()
+                .result,
+            Err(KvError::KeyDoesNotExist)
+        );
+        assert_eq!(
+            client.resolve::<u64>(reply(cas, error(22))).unwrap().result,
+            Err(KvError::PreconditionFailed)
+        );
+        assert!(matches!(
+            client
+                .resolve::<u64>(reply(
+                    wrong_type,
+                    ResponseType::ReadOk(ReadOkBody {
+                        value: Some(json!("alice")),
+                    }),
+                ))
+                .unwrap()
+                .result,
+            Err(KvError::Decode(_))
+        ));
+
+        // Already resolved.
+        assert_eq!(
+            client.resolve::<u64>(reply(read, ResponseType::WriteOk)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_expire_times_out_unanswered() {
+        let mut client = Linearizable::new("n1".to_string(), MessageIds::default())
+            .with_timeout(Duration::from_millis(100));
+        let write = client.write("a", 1).body.msg_id.unwrap();
+        let read = client.read::<u64>("b").body.msg_id.unwrap();
+
+        assert_eq!(
+            client
+                .resolve::<u64>(reply(write, ResponseType::WriteOk))
+                .unwrap()
+                .result,
+            Ok(Reply::Written)
+        );
+        assert!(client.expire::<u64>(Instant::now()).is_empty());
+
+        let expired = client.expire::<u64>(Instant::now() + Duration::from_millis(100));
+        assert_eq!(
+            expired,
+            vec![Outcome {
+                msg_id: read,
+                key: "b".to_string(),
+                result: Err(KvError::Timeout),
+            }]
+        );
+
+        // Too late to count.
+        assert_eq!(
+            client.resolve::<u64>(reply(
+                read,
+                ResponseType::ReadOk(ReadOkBody {
+                    value: Some(json!(1)),
+                }),
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_update_retries_lost_race() {
+        let mut client = Sequential::new("n1".to_string(), MessageIds::default());
+        let mut updates = Updates::new(Retry::default());
+        let now = Instant::now();
+
+        let (id, read) = updates.update(&mut client, "counter", 0u64, |n| n + 5);
+        assert_eq!(
+            read.body.kind,
+            MessageType::Read(ReadBody {
+                key: "counter".to_string()
+            })
+        );
+
+        let outcome = client
+            .resolve(reply(
+                read.body.msg_id.unwrap(),
+                ResponseType::ReadOk(ReadOkBody {
+                    value: Some(json!(1)),
+                }),
+            ))
+            .unwrap();
+        let Some(Progress::Send(cas)) = updates.on_outcome(&mut client, outcome, now) else {
+            panic!("expected a compare-and-swap");
+        };
+        assert_eq!(
+            cas.body.kind,
+            MessageType::CompareAndSwap(CompareAndSwapBody {
+                key: "counter".to_string(),
+                from: 1,
+                to: 6,
+                create_if_not_exists: false,
+            })
+        );
+
+        // Somebody else got there first.
+        let outcome = client
+            .resolve(reply(cas.body.msg_id.unwrap(), error(22)))
+            .unwrap();
+        assert_eq!(
+            updates.on_outcome(&mut client, outcome, now),
+            Some(Progress::Backoff)
+        );
+        assert!(updates.tick(&mut client, now).is_empty());
+
+        let mut reads = updates.tick(&mut client, now + Duration::from_millis(10));
+        assert_eq!(reads.len(), 1);
+        let read = reads.remove(0);
+
+        let outcome = client
+            .resolve(reply(
+                read.body.msg_id.unwrap(),
+                ResponseType::ReadOk(ReadOkBody {
+                    value: Some(json!(3)),
+                }),
+            ))
+            .unwrap();
+        let Some(Progress::Send(cas)) = updates.on_outcome(&mut client, outcome, now) else {
+            panic!("expected a compare-and-swap");
+        };
+        let msg_id = cas.body.msg_id.unwrap();
+        assert!(updates.owns(msg_id));
+
+        let outcome = client
+            .resolve(reply(msg_id, ResponseType::CompareAndSwapOk))
+            .unwrap();
+        assert_eq!(
+            updates.on_outcome(&mut client, outcome, now),
+            Some(Progress::Done(Updated {
+                id,
+                key: "counter".to_string(),
+                result: Ok(8),
+            }))
+        );
+        assert!(!updates.owns(msg_id));
+    }
+
+    #[test]
+    fn test_update_creates_missing_key() {
+        let mut client = Linearizable::new("n1".to_string(), MessageIds::default());
+        let mut updates = Updates::new(Retry::default());
+
+        let (_, read) = updates.update(&mut client, "log", Vec::<u64>::new(), |log| {
+            let mut log = log.clone();
+            log.push(7);
+            log
+        });
+        let outcome = client
+            .resolve(reply(read.body.msg_id.unwrap(), error(20)))
+            .unwrap();
+
+        let Some(Progress::Send(cas)) = updates.on_outcome(&mut client, outcome, Instant::now())
+        else {
+            panic!("expected a compare-and-swap");
+        };
+        assert_eq!(
+            cas.body.kind,
+            MessageType::CompareAndSwap(CompareAndSwapBody {
+                key: "log".to_string(),
+                from: vec![],
+                to: vec![7],
+                create_if_not_exists: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_update_gives_up() {
+        let mut client = Sequential::new("n1".to_string(), MessageIds::default());
+        let mut updates = Updates::new(Retry {
+            max_attempts: 2,
+            ..Retry::default()
+        });
+        let later = Instant::now() + Duration::from_secs(1);
+
+        let (id, mut read) = updates.update(&mut client, "counter", 0u64, |n| n + 1);
+        for attempt in 1..=2 {
+            let outcome = client
+                .resolve(reply(
+                    read.body.msg_id.unwrap(),
+                    ResponseType::ReadOk(ReadOkBody {
+                        value: Some(json!(0)),
+                    }),
+                ))
+                .unwrap();
+            let Some(Progress::Send(cas)) = updates.on_outcome(&mut client, outcome, later) else {
+                panic!("expected a compare-and-swap");
+            };
+
+            let outcome = client
+                .resolve(reply(cas.body.msg_id.unwrap(), error(22)))
+                .unwrap();
+            let progress = updates.on_outcome(&mut client, outcome, later);
+            if attempt == 2 {
+                assert_eq!(
+                    progress,
+                    Some(Progress::Done(Updated {
+                        id,
+                        key: "counter".to_string(),
+                        result: Err(KvError::PreconditionFailed),
+                    }))
+                );
+            } else {
+                assert_eq!(progress, Some(Progress::Backoff));
+                read = updates
+                    .tick(&mut client, later + Duration::from_secs(1))
+                    .remove(0);
+            }
+        }
+
+        // A swap that timed out may have happened, so it is not retried.
+        let (id, read) = updates.update(&mut client, "counter", 0u64, |n| n + 1);
+        let outcome = client
+            .resolve(reply(
+                read.body.msg_id.unwrap(),
+                ResponseType::ReadOk(ReadOkBody {
+                    value: Some(json!(0)),
+                }),
+            ))
+            .unwrap();
+        updates.on_outcome(&mut client, outcome, later);
+        let mut expired = client.expire(later + Duration::from_secs(1));
+        assert_eq!(
+            updates.on_outcome(&mut client, expired.remove(0), later),
+            Some(Progress::Done(Updated {
+                id,
+                key: "counter".to_string(),
+                result: Err(KvError::Timeout),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut client = Sequential::new("n1".to_string(), MessageIds::default()).with_cache();
+        assert_eq!(client.cached::<u64>("a"), None);
+
+        let read = client.read::<u64>("a").body.msg_id.unwrap();
+        client.resolve::<u64>(reply(
+            read,
+            ResponseType::ReadOk(ReadOkBody {
+                value: Some(json!(1)),
+            }),
+        ));
+        assert_eq!(client.cached::<u64>("a"), Some(1));
+
+        // Cached once the service has taken it, not before.
+        let cas = client
+            .compare_and_swap("a", 1, 2, false)
+            .body
+            .msg_id
+            .unwrap();
+        assert_eq!(client.cached::<u64>("a"), Some(1));
+        client.resolve::<u64>(reply(cas, ResponseType::CompareAndSwapOk));
+        assert_eq!(client.cached::<u64>("a"), Some(2));
+
+        let cas = client
+            .compare_and_swap("a", 2, 3, false)
+            .body
+            .msg_id
+            .unwrap();
+        client.resolve::<u64>(reply(cas, error(22)));
+        assert_eq!(client.cached::<u64>("a"), None);
+
+        let write = client.write("b", 5).body.msg_id.unwrap();
+        client.resolve::<u64>(reply(write, ResponseType::WriteOk));
+        assert_eq!(client.cached::<u64>("b"), Some(5));
+
+        client.write("b", 6);
+        client.expire::<u64>(Instant::now() + DEFAULT_TIMEOUT);
+        assert_eq!(client.cached::<u64>("b"), None);
+
+        // A read answered after a later write is older news.
+        let read = client.read::<u64>("c").body.msg_id.unwrap();
+        let write = client.write("c", 2).body.msg_id.unwrap();
+        client.resolve::<u64>(reply(write, ResponseType::WriteOk));
+        client.resolve::<u64>(reply(
+            read,
+            ResponseType::ReadOk(ReadOkBody {
+                value: Some(json!(1)),
+            }),
+        ));
+        assert_eq!(client.cached::<u64>("c"), Some(2));
+
+        // Nor may it bring back a value a later failure dropped.
+        let read = client.read::<u64>("c").body.msg_id.unwrap();
+        let cas = client
+            .compare_and_swap("c", 2, 3, false)
+            .body
+            .msg_id
+            .unwrap();
+        client.resolve::<u64>(reply(cas, error(22)));
+        client.resolve::<u64>(reply(
+            read,
+            ResponseType::ReadOk(ReadOkBody {
+                value: Some(json!(2)),
+            }),
+        ));
+        assert_eq!(client.cached::<u64>("c"), None);
+
+        // A missing value is not cached as null.
+        let read = client.read::<Option<u64>>("d").body.msg_id.unwrap();
+        client.resolve::<Option<u64>>(reply(
+            read,
+            ResponseType::ReadOk(ReadOkBody { value: None }),
+        ));
+        assert_eq!(client.cached::<Option<u64>>("d"), None);
+
+        // Never cached without asking for it, and never served by lin-kv.
+        let mut client = Sequential::new("n1".to_string(), MessageIds::default());
+        let write = client.write("a", 1).body.msg_id.unwrap();
+        client.resolve::<u64>(reply(write, ResponseType::WriteOk));
+        assert_eq!(client.cached::<u64>("a"), None);
+
+        let mut client = Linearizable::new("n1".to_string(), MessageIds::default()).with_cache();
+        let write = client.write("a", 1).body.msg_id.unwrap();
+        client.resolve::<u64>(reply(write, ResponseType::WriteOk));
+        assert_eq!(client.cached::<u64>("a"), None);
+    }
+
+    #[test]
+    fn test_sharded_read_combines_shards() {
+        let cluster = Cluster::from(crate::InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        });
+        let mut client = Sequential::new("n1".to_string(), MessageIds::default());
+        let mut counter = Sharded::per_node("counter", &cluster, 0u64, |a, b| a + b);
+        assert_eq!(counter.keys(), ["counter-n1", "counter-n2", "counter-n3"]);
+        assert_eq!(counter.shard("n2"), "counter-n2");
+
+        let (id, reads) = counter.read(&mut client);
+        let mut gathered = Vec::new();
+        for (read, kind) in reads.iter().zip([
+            ResponseType::ReadOk(ReadOkBody {
+                value: Some(json!(2)),
+            }),
+            error(20),
+            ResponseType::ReadOk(ReadOkBody {
+                value: Some(json!(3)),
+            }),
+        ]) {
+            let msg_id = read.body.msg_id.unwrap();
+            assert!(counter.owns(msg_id));
+            let outcome = client.resolve(reply(msg_id, kind)).unwrap();
+            gathered.extend(counter.on_outcome(outcome));
+        }
+        assert_eq!(gathered, vec![Gathered { id, result: Ok(5) }]);
+
+        // One bad shard fails the whole read.
+        let (id, reads) = counter.read(&mut client);
+        let outcome = client
+            .resolve(reply(reads[1].body.msg_id.unwrap(), error(13)))
+            .unwrap();
+        assert!(matches!(
+            counter.on_outcome(outcome),
+            Some(Gathered { id: failed, result: Err(KvError::Other { code: 13, .. }) }) if failed == id
+        ));
+        assert!(!counter.owns(reads[0].body.msg_id.unwrap()));
+    }
+
+    #[test]
+    fn test_sharded_buckets() {
+        let mut client = Sequential::new("n1".to_string(), MessageIds::default());
+        let mut updates = Updates::new(Retry::default());
+        let ids = Sharded::buckets("ids", 4, 0u64, u64::max);
+
+        // Writers stick to one bucket each, and spread across them.
+        let shards: HashSet<String> = (0..32)
+            .map(|i| ids.shard(&format!("n{i}")).to_string())
+            .collect();
+        assert!(shards.iter().all(|shard| ids.keys().contains(shard)));
+        assert_eq!(ids.shard("n1"), ids.shard("n1"));
+        assert!(shards.len() > 1);
+
+        let (_, read) = ids.update(&mut updates, &mut client, "n1", |n| n + 100);
+        assert_eq!(
+            read.body.kind,
+            MessageType::Read(ReadBody {
+                key: ids.shard("n1").to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_memory_staleness_and_cas_failures() {
+        let store = MemoryStore::default();
+        let mut writer: Memory<SeqKv> = Memory::new("n1".to_string(), &store);
+        let mut reader: Memory<SeqKv> = Memory::new("n2".to_string(), &store).with_staleness();
+
+        writer.write("a", 1);
+        reader.read::<u64>("a");
+        let [write_ok] = &writer.replies()[..] else {
+            panic!("expected one reply");
+        };
+        assert_eq!(write_ok.body.kind, ResponseType::WriteOk);
+        let [stale] = &reader.replies()[..] else {
+            panic!("expected one reply");
+        };
+        assert_eq!(stale.body.kind, error(20));
+
+        // Writing catches the reader up.
+        reader.write("b", 2);
+        reader.read::<u64>("a");
+        let replies = reader.replies();
+        let outcome = reader.resolve::<u64>(replies.into_iter().nth(1).unwrap());
+        assert_eq!(outcome.unwrap().result, Ok(Reply::Read(1)));
+
+        writer.fail_cas(1);
+        writer.compare_and_swap("a", 1, 2, false);
+        writer.compare_and_swap("a", 1, 2, false);
+        writer.compare_and_swap("c", 1, 2, false);
+        let kinds: Vec<_> = writer.replies().into_iter().map(|r| r.body.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![error(22), ResponseType::CompareAndSwapOk, error(20)]
+        );
+        assert_eq!(store.get("a"), Some(json!(2)));
+    }
 }
@@ -1,13 +1,60 @@
 use anyhow::Context;
-use serde_json::{Serializer, ser::Formatter};
-use std::io::{self, BufRead, StdoutLock, Write};
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
+use codec::{Codec, JsonLines, Links};
+use dedup::{Dedup, Seen};
+use limit::{Limits, Shedder, Throttle};
+use storage::Store;
+
+pub mod cluster;
+pub mod codec;
+pub mod crdt;
+mod dedup;
+pub mod detector;
+pub mod election;
+pub mod gossip;
 pub mod kv;
+pub mod limit;
+pub mod raft;
+#[cfg(test)]
+mod sim;
+pub mod storage;
+pub mod tso;
+pub mod txn;
 
 pub type MessageID = u64;
 
+/// Hands out the `msg_id`s for everything a node sends.
+///
+/// Clones share one counter, so a node can give a clone to each service
+/// client it runs and use another for its own messages, and replies are
+/// still never matched to the wrong request.
+#[derive(Clone, Debug)]
+pub struct MessageIds(Rc<Cell<MessageID>>);
+
+impl MessageIds {
+    pub fn next(&self) -> MessageID {
+        let id = self.0.get();
+        self.0.set(id + 1);
+        id
+    }
+}
+
+impl Default for MessageIds {
+    fn default() -> Self {
+        Self(Rc::new(Cell::new(1)))
+    }
+}
+
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct InitBody {
     pub node_id: String,
@@ -82,28 +129,6 @@ pub struct Response<Type> {
     pub body: ResponseBody<Type>,
 }
 
-#[derive(Default)]
-pub struct JSONLFormatter {
-    depth: usize,
-}
-
-impl Formatter for JSONLFormatter {
-    fn begin_object<W: ?Sized + Write>(&mut self, w: &mut W) -> io::Result<()> {
-        self.depth += 1;
-        w.write_all(b"{")
-    }
-
-    fn end_object<W: ?Sized + Write>(&mut self, w: &mut W) -> io::Result<()> {
-        self.depth -= 1;
-
-        if self.depth == 0 {
-            w.write_all(b"}\n")
-        } else {
-            w.write_all(b"}")
-        }
-    }
-}
-
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct ErrorBody {
     pub code: u32,
@@ -111,6 +136,19 @@ pub struct ErrorBody {
     pub in_reply_to: Option<MessageID>,
 }
 
+impl ErrorBody {
+    /// The node could not handle the request right now; the client may try
+    /// again later.
+    pub const TEMPORARILY_UNAVAILABLE: u32 = 11;
+    /// The request timed out; it may or may not have taken effect.
+    pub const TIMEOUT: u32 = 0;
+    pub const KEY_DOES_NOT_EXIST: u32 = 20;
+    /// A compare-and-swap found a different value than expected.
+    pub const PRECONDITION_FAILED: u32 = 22;
+    /// A transaction was aborted because another one got in first.
+    pub const TXN_CONFLICT: u32 = 30;
+}
+
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ErrorMessageType {
@@ -120,12 +158,109 @@ pub enum ErrorMessageType {
 #[derive(Debug)]
 pub enum Service {
     KeyValue(Response<kv::ResponseType>),
+    Timestamp(Response<tso::ResponseType>),
+}
+
+/// Collects the messages a node emits while handling a single event.
+///
+/// Nothing reaches stdout until the runtime flushes the batch at the end of
+/// the event, so a burst of gossip costs one write rather than one per line.
+#[derive(Default)]
+pub struct Output {
+    buf: Vec<u8>,
+    store: Option<Store>,
+    throttle: Option<Throttle>,
+    links: Links,
 }
 
-pub type Output<'a> = Serializer<StdoutLock<'a>, JSONLFormatter>;
+impl Output {
+    /// Queues `message` for the end of the event. Messages to other nodes
+    /// over the rates in [`Node::limits`] are dropped, and `false` says so.
+    ///
+    /// Each message is encoded with the codec [`Links`] picks for its `dest`,
+    /// which is JSON lines unless [`codec::PEER_CODEC_VAR`] says otherwise for
+    /// other nodes.
+    pub fn send<T: Serialize>(&mut self, message: &T) -> anyhow::Result<bool> {
+        if let Some(throttle) = &mut self.throttle
+            && !throttle.admit(message, Instant::now())
+        {
+            return Ok(false);
+        }
+
+        if self.links.all_json_lines() {
+            JsonLines.encode(message, &mut self.buf)?;
+        } else {
+            let dst = limit::field(message, &["dest"]).unwrap_or_default();
+            self.links.codec(&dst).encode(message, &mut self.buf)?;
+        }
+        Ok(true)
+    }
+
+    /// Appends a record to the node's write-ahead log, to be handed back to
+    /// [`Node::replay`] after a restart. A no-op when storage is disabled.
+    ///
+    /// The log is synced before this event's messages are written, so any
+    /// reply acknowledging the mutation is only sent once it is durable.
+    pub fn persist(&mut self, record: &[u8]) -> anyhow::Result<()> {
+        match &mut self.store {
+            Some(store) => store.append(record),
+            None => Ok(()),
+        }
+    }
+
+    /// Snapshots the node if enough has been logged since the last snapshot.
+    fn checkpoint<N: Node<T>, T>(&mut self, node: &N) -> anyhow::Result<()> {
+        let Some(store) = &mut self.store else {
+            return Ok(());
+        };
+
+        if !store.snapshot_due() {
+            return Ok(());
+        }
+
+        let snapshot = node.snapshot();
+        if snapshot.is_empty() {
+            store.defer_snapshot();
+            return Ok(());
+        }
+
+        store.snapshot(&snapshot).context("taking snapshot")
+    }
+
+    fn flush<W: Write>(&mut self, w: &mut W) -> anyhow::Result<()> {
+        if let Some(store) = &mut self.store {
+            store.sync()?;
+        }
+
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        w.write_all(&self.buf)?;
+        w.flush()?;
+        self.buf.clear();
+
+        Ok(())
+    }
+}
 
 pub trait Node<MessageType> {
-    fn init(message: InitBody) -> Self;
+    /// How often the runtime calls [`Node::on_tick`], whether or not any
+    /// messages arrive. `None` means the node is only driven by its input.
+    const TICK_INTERVAL: Option<Duration> = None;
+
+    /// How many recent requests the runtime remembers, by `src` and
+    /// `msg_id`, so that a retry is answered with the original reply instead
+    /// of reaching [`Node::on_message`] again. `None` passes every message
+    /// through, which is fine for nodes whose operations are idempotent, and
+    /// so does `Some(0)`.
+    const DEDUP_WINDOW: Option<usize> = None;
+
+    /// Settings chosen before the node starts, such as from the command
+    /// line, and handed to [`Node::init`] by [`run`].
+    type Config;
+
+    fn init(message: InitBody, config: Self::Config) -> Self;
 
     fn on_message(
         &mut self,
@@ -144,17 +279,95 @@ pub trait Node<MessageType> {
     ) -> anyhow::Result<()> {
         Ok(())
     }
+
+    fn on_tick(&mut self, _output: &mut Output) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Rate limits and load shedding for the runtime to enforce.
+    fn limits() -> Limits {
+        Limits::default()
+    }
+
+    /// Called after [`Node::init`] with each record the node previously
+    /// passed to [`Output::persist`], oldest first.
+    fn replay(&mut self, _record: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Serializes the node's whole state, letting the runtime discard the
+    /// log records that led up to it. Nodes that return nothing are never
+    /// snapshotted and keep their full log.
+    fn snapshot(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Called after [`Node::init`] with the latest snapshot, before any log
+    /// records written since it are replayed.
+    fn restore(&mut self, _snapshot: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A Maelstrom message with its body left undecoded, so the runtime can
+/// route on `src` and `body.type` before committing to a body type.
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(borrow)]
+    src: Cow<'a, str>,
+    #[serde(rename = "dest", borrow)]
+    dst: Cow<'a, str>,
+    #[serde(borrow)]
+    body: &'a RawValue,
+}
+
+#[derive(Deserialize)]
+struct Tag<'a> {
+    #[serde(rename = "type", borrow)]
+    kind: Cow<'a, str>,
+}
+
+#[derive(Deserialize)]
+struct Ids {
+    msg_id: Option<MessageID>,
+    in_reply_to: Option<MessageID>,
+}
+
+impl<'a> Envelope<'a> {
+    fn kind(&self) -> anyhow::Result<Cow<'a, str>> {
+        let tag: Tag = serde_json::from_str(self.body.get()).context("reading message type")?;
+        Ok(tag.kind)
+    }
+
+    fn ids(&self) -> anyhow::Result<Ids> {
+        serde_json::from_str(self.body.get()).context("reading message ids")
+    }
+
+    fn into_message<Type: DeserializeOwned>(self) -> anyhow::Result<Message<Type>> {
+        Ok(Message {
+            body: serde_json::from_str(self.body.get()).context("deserializing message body")?,
+            src: self.src.into_owned(),
+            dst: self.dst.into_owned(),
+        })
+    }
+
+    fn into_response<Type: DeserializeOwned>(self) -> anyhow::Result<Response<Type>> {
+        Ok(Response {
+            body: serde_json::from_str(self.body.get()).context("deserializing response body")?,
+            src: self.src.into_owned(),
+            dst: self.dst.into_owned(),
+        })
+    }
 }
 
-pub fn run<N, Type>() -> anyhow::Result<()>
+pub fn run<N, Type>(config: N::Config) -> anyhow::Result<()>
 where
     N: Node<Type>,
     Type: DeserializeOwned,
 {
     let mut input = std::io::stdin().lock();
 
-    let mut output =
-        serde_json::Serializer::with_formatter(std::io::stdout().lock(), JSONLFormatter::default());
+    let mut output = Output::default();
 
     let mut buf = String::new();
     input.read_line(&mut buf).context("reading init message")?;
@@ -173,43 +386,175 @@ where
         },
     };
 
-    reply
-        .serialize(&mut output)
-        .context("serializing init_ok response")?;
+    let storage = storage::Options::from_env()?;
+    let node_id = init_body.node_id.clone();
 
-    let mut node: N = Node::init(init_body);
+    let limits = N::limits();
+    let peers: Vec<String> = init_body
+        .node_ids
+        .iter()
+        .filter(|&n| n != &node_id)
+        .cloned()
+        .collect();
+    output.throttle = Throttle::new(&limits, peers.clone());
+    output.links = Links::from_env(peers)?;
+    let shedder = Shedder::new(&limits, &node_id);
 
-    for line in input.lines() {
-        let line = line.context("reading from stdin")?;
-        let raw: serde_json::Value =
-            serde_json::from_str(&line).context("could not deserialize Maelstrom input as JSON")?;
+    let mut node: N = Node::init(init_body, config);
 
-        if let Some(message) = raw.as_object() {
-            let src = message["src"].as_str().unwrap_or("");
-            let msg_type = message["body"]["type"].as_str().unwrap_or("");
+    if let Some(storage) = storage {
+        let (store, recovery) = Store::open(&storage, &node_id).context("opening storage")?;
+
+        if let Some(snapshot) = recovery.snapshot {
+            node.restore(&snapshot).context("restoring snapshot")?;
+        }
+
+        for record in recovery.records {
+            node.replay(&record).context("replaying write-ahead log")?;
+        }
 
-            match msg_type {
+        output.store = Some(store);
+    }
+
+    output
+        .send(&reply)
+        .context("serializing init_ok response")?;
+    output
+        .flush(&mut io::stdout().lock())
+        .context("flushing init_ok response")?;
+
+    drop(input);
+    let (lines, rejections) = read_lines(shedder.clone());
+
+    let mut next_tick = N::TICK_INTERVAL.map(|interval| Instant::now() + interval);
+    let mut dedup = N::DEDUP_WINDOW.and_then(Dedup::new);
+
+    loop {
+        let line = match next_tick {
+            Some(at) if Instant::now() >= at => {
+                next_tick = N::TICK_INTERVAL.map(|interval| Instant::now() + interval);
+                node.on_tick(&mut output)?;
+                None
+            }
+            Some(at) => match lines.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                Ok(line) => Some(line),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match lines.recv() {
+                Ok(line) => Some(line),
+                Err(_) => break,
+            },
+        };
+
+        if let Some(line) = line {
+            if let Some(shedder) = &shedder {
+                shedder.handled();
+            }
+            let line = line.context("reading from stdin")?;
+            let envelope: Envelope = serde_json::from_str(&line)
+                .context("could not deserialize Maelstrom input as JSON")?;
+
+            match envelope.kind()?.as_ref() {
+                "read_ok" | "write_ok" | "cas_ok" | "error"
+                    if kv::SERVICES.contains(&envelope.src.as_ref()) =>
+                {
+                    let msg: Response<kv::ResponseType> = envelope.into_response()?;
+                    node.on_service(Service::KeyValue(msg), &mut output)?;
+                }
+                "ts_ok" | "error" if envelope.src == tso::SERVICE => {
+                    let msg: Response<tso::ResponseType> = envelope.into_response()?;
+                    node.on_service(Service::Timestamp(msg), &mut output)?;
+                }
                 "error" => {
-                    let msg: Message<ErrorMessageType> = serde_json::from_value(raw)?;
+                    let msg: Message<ErrorMessageType> = envelope.into_message()?;
                     node.on_error(msg, &mut output)?;
                 }
-                "read_ok" | "write_ok" | "cas_ok" if src == "seq-kv" => {
-                    let msg: Response<kv::ResponseType> = serde_json::from_value(raw)?;
-                    node.on_service(Service::KeyValue(msg), &mut output)?;
-                }
                 _ => {
-                    let msg: Message<Type> = serde_json::from_value(raw)?;
-                    node.on_message(msg, &mut output)?;
+                    let seen = match (&mut dedup, envelope.ids()?.msg_id) {
+                        (Some(dedup), Some(msg_id)) => {
+                            dedup.check(&envelope.src, msg_id, Instant::now())
+                        }
+                        _ => Seen::New,
+                    };
+
+                    match seen {
+                        Seen::New => {
+                            let msg: Message<Type> = envelope.into_message()?;
+                            node.on_message(msg, &mut output)?;
+                        }
+                        Seen::Pending => {}
+                        Seen::Replied(reply) => output.buf.extend(reply),
+                    }
                 }
             }
-        } else {
-            anyhow::bail!("malformed message {}", raw)
         }
+
+        if let Some(dedup) = &mut dedup {
+            dedup.observe(&output.buf);
+        }
+
+        // After the replies are recorded, so that shedding a retry of a
+        // request still in flight does not stand in for its real answer.
+        for rejection in rejections.try_iter() {
+            output.send(&rejection).context("serializing rejection")?;
+        }
+
+        output.checkpoint(&node)?;
+        output
+            .flush(&mut io::stdout().lock())
+            .context("writing to stdout")?;
     }
 
     Ok(())
 }
 
+/// Lines read from stdin, and the rejections for those that were shed.
+type Lines = (
+    Receiver<io::Result<String>>,
+    Receiver<Message<ErrorMessageType>>,
+);
+
+/// Reads stdin on its own thread, so the runtime can wait for either the
+/// next line or the next tick.
+///
+/// With a `shedder`, requests it rejects are never queued. Their rejections
+/// come back on a channel of their own, which the runtime drains after every
+/// event, so they are not stuck behind the backlog that caused them.
+fn read_lines(shedder: Option<Shedder>) -> Lines {
+    let (tx, rx) = mpsc::channel();
+    let (rejected_tx, rejected_rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut input = std::io::stdin().lock();
+
+        loop {
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    if let Some(rejection) = shedder.as_ref().and_then(|s| s.admit(&line)) {
+                        if rejected_tx.send(rejection).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    if tx.send(Ok(line)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    break;
+                }
+            }
+        }
+    });
+
+    (rx, rejected_rx)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -242,4 +587,105 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_envelope_routes_without_decoding_body() {
+        let line =
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"read_ok","value":3,"in_reply_to":4}}"#;
+
+        let envelope: Envelope = serde_json::from_str(line).unwrap();
+        assert_eq!(envelope.src, "seq-kv");
+        assert_eq!(envelope.kind().unwrap(), "read_ok");
+
+        assert_eq!(
+            envelope.into_response::<kv::ResponseType>().unwrap(),
+            Response {
+                src: "seq-kv".to_string(),
+                dst: "n1".to_string(),
+                body: ResponseBody {
+                    kind: kv::ResponseType::ReadOk(kv::ReadOkBody {
+                        value: Some(serde_json::json!(3)),
+                    }),
+                    msg_id: None,
+                    in_reply_to: Some(4),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_envelope_rejects_missing_fields() {
+        assert!(
+            serde_json::from_str::<Envelope>(r#"{"dest":"n1","body":{"type":"read"}}"#).is_err()
+        );
+
+        let envelope: Envelope =
+            serde_json::from_str(r#"{"src":"c1","dest":"n1","body":{"msg_id":1}}"#).unwrap();
+        assert!(envelope.kind().is_err());
+    }
+
+    #[test]
+    fn test_output_encodes_per_link() {
+        let mut output = Output {
+            links: Links::new(["n2".to_string()], codec::WireCodec::MessagePack),
+            ..Output::default()
+        };
+        let message = |dst: &str| Message {
+            src: "n1".to_string(),
+            dst: dst.to_string(),
+            body: MessageBody {
+                kind: ErrorMessageType::Error(ErrorBody {
+                    code: ErrorBody::TEMPORARILY_UNAVAILABLE,
+                    text: "busy".to_string(),
+                    in_reply_to: Some(1),
+                }),
+                msg_id: None,
+            },
+        };
+
+        output.send(&message("n2")).unwrap();
+        output.send(&message("c1")).unwrap();
+
+        let mut r = output.buf.as_slice();
+        assert_eq!(
+            codec::MessagePack.decode(&mut r).unwrap(),
+            Some(message("n2"))
+        );
+        assert_eq!(JsonLines.decode(&mut r).unwrap(), Some(message("c1")));
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn test_output_batches_until_flush() {
+        let mut output = Output::default();
+        let mut stdout = Vec::new();
+
+        for msg_id in 1..=2 {
+            output
+                .send(&Response {
+                    src: "n1".to_string(),
+                    dst: "c1".to_string(),
+                    body: ResponseBody {
+                        kind: ResponseType::InitOk,
+                        msg_id: None,
+                        in_reply_to: Some(msg_id),
+                    },
+                })
+                .unwrap();
+        }
+        assert!(stdout.is_empty());
+
+        output.flush(&mut stdout).unwrap();
+        output.flush(&mut stdout).unwrap();
+
+        assert_eq!(
+            String::from_utf8(stdout).unwrap(),
+            concat!(
+                r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","msg_id":null,"in_reply_to":1}}"#,
+                "\n",
+                r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","msg_id":null,"in_reply_to":2}}"#,
+                "\n",
+            )
+        );
+    }
 }
@@ -0,0 +1,511 @@
+//! Keeps a node from falling arbitrarily far behind under load: token
+//! buckets cap what it sends to other nodes, and a bounded inbound queue
+//! turns client requests away with `temporarily-unavailable` once full.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use serde::{
+    Serialize, Serializer,
+    ser::{self, Impossible},
+};
+
+use crate::{Envelope, ErrorBody, ErrorMessageType, Message, MessageBody};
+
+/// A sustained rate with some headroom for bursts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// What [`crate::Node::limits`] asks the runtime to enforce. The default
+/// enforces nothing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// Rates for messages to other nodes, by body type, each applied to
+    /// every link separately. Messages over the rate are dropped, so this
+    /// only suits traffic that is retried or repeated anyway, like gossip.
+    pub outbound: HashMap<String, Rate>,
+    /// The rate for messages to other nodes whose type has no entry in
+    /// `outbound`; `None` leaves them unlimited.
+    pub outbound_default: Option<Rate>,
+    /// How many inbound messages may wait to be handled before client
+    /// requests are rejected; `None` never rejects any.
+    pub inbound_capacity: Option<usize>,
+    /// The client request types to reject when the queue is full. Empty
+    /// means all of them. Messages from other nodes are never rejected.
+    pub shed: HashSet<String>,
+}
+
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst,
+            last: now,
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// The outbound half of [`Limits`], applied to each message before it is
+/// encoded.
+pub(crate) struct Throttle {
+    peers: HashSet<String>,
+    rates: HashMap<String, Rate>,
+    default: Option<Rate>,
+    buckets: HashMap<(String, String), TokenBucket>,
+}
+
+impl Throttle {
+    /// `None` when `limits` has no outbound rates to enforce.
+    pub(crate) fn new(limits: &Limits, peers: impl IntoIterator<Item = String>) -> Option<Self> {
+        if limits.outbound.is_empty() && limits.outbound_default.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            peers: peers.into_iter().collect(),
+            rates: limits.outbound.clone(),
+            default: limits.outbound_default,
+            buckets: HashMap::new(),
+        })
+    }
+
+    /// Whether `message` may be sent now.
+    pub(crate) fn admit<T: Serialize + ?Sized>(&mut self, message: &T, now: Instant) -> bool {
+        let Some(dst) = field(message, &["dest"]) else {
+            return true;
+        };
+        if !self.peers.contains(&dst) {
+            return true;
+        }
+        let Some(kind) = field(message, &["body", "type"]) else {
+            return true;
+        };
+        let Some(rate) = self.rates.get(&kind).or(self.default.as_ref()) else {
+            return true;
+        };
+
+        self.buckets
+            .entry((dst, kind))
+            .or_insert_with(|| TokenBucket::new(*rate, now))
+            .try_take(now)
+    }
+}
+
+/// The string at `path` in `value`'s serialized form, such as a message's
+/// destination or body type, found without serializing anything else.
+pub(crate) fn field<T: Serialize + ?Sized>(value: &T, path: &[&str]) -> Option<String> {
+    value.serialize(Probe { path }).ok().flatten()
+}
+
+/// A serializer that walks down `path` through structs and maps, skipping
+/// every other field, and returns the string it ends on.
+struct Probe<'a> {
+    path: &'a [&'a str],
+}
+
+#[derive(Debug)]
+struct NotFound;
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no string at that path")
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+impl ser::Error for NotFound {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        NotFound
+    }
+}
+
+macro_rules! not_found {
+    ($($method:ident($($ty:ty),*)),* $(,)?) => {
+        $(fn $method(self, $(_: $ty),*) -> Result<Option<String>, NotFound> {
+            Ok(None)
+        })*
+    };
+}
+
+impl<'a> Serializer for Probe<'a> {
+    type Ok = Option<String>;
+    type Error = NotFound;
+    type SerializeSeq = Impossible<Option<String>, NotFound>;
+    type SerializeTuple = Impossible<Option<String>, NotFound>;
+    type SerializeTupleStruct = Impossible<Option<String>, NotFound>;
+    type SerializeTupleVariant = Impossible<Option<String>, NotFound>;
+    type SerializeMap = Fields<'a>;
+    type SerializeStruct = Fields<'a>;
+    type SerializeStructVariant = Impossible<Option<String>, NotFound>;
+
+    not_found! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+        serialize_unit_variant(&'static str, u32, &'static str),
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Option<String>, NotFound> {
+        Ok(self.path.is_empty().then(|| v.to_string()))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Option<String>, NotFound> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Option<String>, NotFound> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Option<String>, NotFound> {
+        Ok(None)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, NotFound> {
+        Err(NotFound)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, NotFound> {
+        Err(NotFound)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, NotFound> {
+        Err(NotFound)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, NotFound> {
+        Err(NotFound)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Fields<'a>, NotFound> {
+        self.fields()
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Fields<'a>, NotFound> {
+        self.fields()
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, NotFound> {
+        Err(NotFound)
+    }
+}
+
+impl<'a> Probe<'a> {
+    fn fields(self) -> Result<Fields<'a>, NotFound> {
+        let (&name, rest) = self.path.split_first().ok_or(NotFound)?;
+
+        Ok(Fields {
+            name,
+            rest,
+            matched: false,
+            found: None,
+        })
+    }
+}
+
+/// Looks for the field called `name`, then follows `rest` inside it.
+struct Fields<'a> {
+    name: &'a str,
+    rest: &'a [&'a str],
+    /// Whether the map key just seen was `name`.
+    matched: bool,
+    found: Option<String>,
+}
+
+impl Fields<'_> {
+    fn visit<T: Serialize + ?Sized>(&mut self, value: &T) {
+        if self.found.is_none() {
+            self.found = field(value, self.rest);
+        }
+    }
+}
+
+impl ser::SerializeStruct for Fields<'_> {
+    type Ok = Option<String>;
+    type Error = NotFound;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), NotFound> {
+        if key == self.name {
+            self.visit(value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Option<String>, NotFound> {
+        Ok(self.found)
+    }
+}
+
+impl ser::SerializeMap for Fields<'_> {
+    type Ok = Option<String>;
+    type Error = NotFound;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), NotFound> {
+        self.matched = field(key, &[]).as_deref() == Some(self.name);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), NotFound> {
+        if self.matched {
+            self.visit(value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Option<String>, NotFound> {
+        Ok(self.found)
+    }
+}
+
+/// The inbound half of [`Limits`], shared between the thread reading stdin
+/// and the runtime loop, which report lines queued and handled.
+#[derive(Clone)]
+pub(crate) struct Shedder {
+    node_id: String,
+    capacity: usize,
+    shed: HashSet<String>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// Maelstrom names its clients c1, c2, ..., as it names nodes n1, n2, ...
+fn is_client(src: &str) -> bool {
+    src.strip_prefix('c')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+impl Shedder {
+    pub(crate) fn new(limits: &Limits, node_id: &str) -> Option<Self> {
+        Some(Self {
+            node_id: node_id.to_string(),
+            capacity: limits.inbound_capacity?,
+            shed: limits.shed.clone(),
+            queued: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Queues `line`, unless the queue is full and it is a client request we
+    /// may shed, in which case this returns the error to answer it with.
+    pub(crate) fn admit(&self, line: &str) -> Option<Message<ErrorMessageType>> {
+        if self.queued.load(Ordering::Acquire) >= self.capacity
+            && let Some(rejection) = self.reject(line)
+        {
+            return Some(rejection);
+        }
+
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        None
+    }
+
+    pub(crate) fn handled(&self) {
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    fn reject(&self, line: &str) -> Option<Message<ErrorMessageType>> {
+        let envelope: Envelope = serde_json::from_str(line).ok()?;
+        if !is_client(&envelope.src) {
+            return None;
+        }
+        let kind = envelope.kind().ok()?;
+        if !self.shed.is_empty() && !self.shed.contains(kind.as_ref()) {
+            return None;
+        }
+        // Nobody is waiting for an answer to a message without an id.
+        let msg_id = envelope.ids().ok()?.msg_id?;
+
+        Some(Message {
+            src: self.node_id.clone(),
+            dst: envelope.src.into_owned(),
+            body: MessageBody {
+                kind: ErrorMessageType::Error(ErrorBody {
+                    code: ErrorBody::TEMPORARILY_UNAVAILABLE,
+                    text: format!("{} is overloaded", self.node_id),
+                    in_reply_to: Some(msg_id),
+                }),
+                msg_id: None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const RATE: Rate = Rate {
+        per_second: 10.0,
+        burst: 2.0,
+    };
+
+    fn line(src: &str, dst: &str, kind: &str) -> String {
+        format!(r#"{{"src":"{src}","dest":"{dst}","body":{{"type":"{kind}","msg_id":7}}}}"#)
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(RATE, now);
+
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        assert!(bucket.try_take(now + Duration::from_millis(100)));
+        assert!(!bucket.try_take(now + Duration::from_millis(100)));
+
+        // Idle time only refills up to the burst.
+        let later = now + Duration::from_secs(10);
+        assert_eq!((0..3).filter(|_| bucket.try_take(later)).count(), 2);
+    }
+
+    #[test]
+    fn test_throttle_limits_peer_traffic_by_type() {
+        let limits = Limits {
+            outbound: HashMap::from([("gossip".to_string(), RATE)]),
+            ..Limits::default()
+        };
+        let mut throttle = Throttle::new(&limits, ["n2".to_string(), "n3".to_string()]).unwrap();
+        let now = Instant::now();
+
+        let admitted = |throttle: &mut Throttle, line: String| {
+            let message: serde_json::Value = serde_json::from_str(&line).unwrap();
+            (0..5).filter(|_| throttle.admit(&message, now)).count()
+        };
+        assert_eq!(admitted(&mut throttle, line("n1", "n2", "gossip")), 2);
+        assert_eq!(admitted(&mut throttle, line("n1", "n3", "gossip")), 2);
+        assert_eq!(admitted(&mut throttle, line("n1", "n2", "ping")), 5);
+        assert_eq!(admitted(&mut throttle, line("n1", "c1", "gossip")), 5);
+
+        assert!(Throttle::new(&Limits::default(), []).is_none());
+    }
+
+    #[test]
+    fn test_field_reads_routing_without_the_rest() {
+        let message = Message {
+            src: "n1".to_string(),
+            dst: "n2".to_string(),
+            body: MessageBody {
+                kind: ErrorMessageType::Error(ErrorBody {
+                    code: 11,
+                    text: "busy".to_string(),
+                    in_reply_to: None,
+                }),
+                msg_id: Some(3),
+            },
+        };
+
+        assert_eq!(field(&message, &["dest"]).as_deref(), Some("n2"));
+        assert_eq!(field(&message, &["body", "type"]).as_deref(), Some("error"));
+        assert_eq!(field(&message, &["body", "code"]), None);
+        assert_eq!(field(&message, &["body"]), None);
+        assert_eq!(field(&"n1", &[]).as_deref(), Some("n1"));
+    }
+
+    #[test]
+    fn test_shedder_rejects_client_requests_when_full() {
+        let limits = Limits {
+            inbound_capacity: Some(1),
+            shed: HashSet::from(["add".to_string()]),
+            ..Limits::default()
+        };
+        let shedder = Shedder::new(&limits, "n1").unwrap();
+
+        assert_eq!(shedder.admit(&line("c1", "n1", "add")), None);
+
+        let error = shedder.admit(&line("c1", "n1", "add")).unwrap();
+        assert_eq!(error.dst, "c1");
+        assert_eq!(
+            error.body.kind,
+            ErrorMessageType::Error(ErrorBody {
+                code: 11,
+                text: "n1 is overloaded".to_string(),
+                in_reply_to: Some(7),
+            })
+        );
+
+        // Reads and peer traffic still queue.
+        assert_eq!(shedder.admit(&line("c1", "n1", "read")), None);
+        assert_eq!(shedder.admit(&line("n2", "n1", "add")), None);
+        assert_eq!(shedder.admit(&line("coordinator", "n1", "add")), None);
+        assert_eq!(shedder.admit(&line("c", "n1", "add")), None);
+
+        for _ in 0..5 {
+            shedder.handled();
+        }
+        assert_eq!(shedder.admit(&line("c1", "n1", "add")), None);
+
+        assert!(Shedder::new(&Limits::default(), "n1").is_none());
+    }
+}
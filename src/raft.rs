@@ -0,0 +1,758 @@
+use std::{collections::HashMap, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cluster::Cluster,
+    election::{self, Election, ElectionMessage, LeaderChange, Term, Timeouts},
+};
+
+pub type LogIndex = u64;
+
+/// The term and index of a node's last log entry. Candidates only win votes
+/// from nodes whose logs are no further along than theirs.
+pub type Position = (Term, LogIndex);
+
+/// The replicated service. Every node applies the same committed commands in
+/// the same order, so every copy of the state machine ends up the same.
+pub trait StateMachine {
+    type Command: Clone;
+    type Output;
+
+    fn apply(&mut self, command: &Self::Command) -> Self::Output;
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LogEntry<C> {
+    pub term: Term,
+    /// `None` for the no-op a new leader appends to commit its predecessors'
+    /// entries.
+    pub command: Option<C>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AppendEntriesBody<C> {
+    pub term: Term,
+    pub prev_log_index: LogIndex,
+    pub prev_log_term: Term,
+    pub entries: Vec<LogEntry<C>>,
+    pub leader_commit: LogIndex,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AppendEntriesOkBody {
+    pub term: Term,
+    pub success: bool,
+    /// On success, the index of the last entry the follower now shares with
+    /// the leader. On failure, a hint of where the leader should back up to.
+    pub match_index: LogIndex,
+}
+
+/// Messages exchanged between Raft peers. Embed them in a node's own message
+/// type with `#[serde(untagged)]`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RaftMessage<C> {
+    AppendEntries(AppendEntriesBody<C>),
+    AppendEntriesOk(AppendEntriesOkBody),
+    #[serde(untagged)]
+    Election(ElectionMessage<Position>),
+}
+
+/// A change to the state Raft needs back after a restart: the current term,
+/// the vote cast in it, and the log.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record<C> {
+    Vote {
+        term: Term,
+        voted_for: Option<String>,
+    },
+    /// Replaces the log from `from` onwards with `entries`.
+    Entries {
+        from: LogIndex,
+        entries: Vec<LogEntry<C>>,
+    },
+}
+
+/// A committed entry, applied to the state machine.
+#[derive(Debug, PartialEq)]
+pub struct Applied<O> {
+    pub index: LogIndex,
+    /// The term the entry was proposed in. A leader that proposed at `index`
+    /// in an earlier term has lost that proposal if the terms differ.
+    pub term: Term,
+    pub output: O,
+}
+
+/// What the node has to act on after driving Raft.
+#[derive(Debug, PartialEq)]
+pub struct Step<C, O> {
+    /// Changes to make durable, in order, before any of `messages` is sent.
+    /// Passing them to [`crate::Output::persist`] while handling the same
+    /// event does that.
+    pub persist: Vec<Record<C>>,
+    pub messages: Vec<(String, RaftMessage<C>)>,
+    /// Entries committed and applied during this step, in log order.
+    pub applied: Vec<Applied<O>>,
+    pub leader_change: Option<LeaderChange>,
+}
+
+impl<C, O> Default for Step<C, O> {
+    fn default() -> Self {
+        Self {
+            persist: Vec::new(),
+            messages: Vec::new(),
+            applied: Vec::new(),
+            leader_change: None,
+        }
+    }
+}
+
+pub type StepFor<S> = Step<<S as StateMachine>::Command, <S as StateMachine>::Output>;
+
+#[derive(Debug, PartialEq)]
+pub struct NotLeader {
+    /// Where to send the command instead, if a leader is known.
+    pub leader: Option<String>,
+}
+
+/// A Raft peer replicating a log of commands into a [`StateMachine`].
+///
+/// Leader election is [`Election`], with log replication messages standing
+/// in for its heartbeats. Like [`Election`] it does no I/O: the node feeds it
+/// ticks and peer messages and sends whatever [`Step`] comes back.
+///
+/// Every change to the term, vote or log comes back as a [`Record`] in the
+/// same step as the messages that depend on it. A node that restarts hands
+/// the records back to [`Raft::replay`] and rejoins with the votes it cast
+/// and the entries it acknowledged. Which entries are committed is learned
+/// again from the leader, and the state machine is rebuilt as they are.
+pub struct Raft<S: StateMachine> {
+    election: Election<Position>,
+    log: Vec<LogEntry<S::Command>>,
+    /// The term and vote as last recorded.
+    recorded: (Term, Option<String>),
+    /// The first log index changed since the log was last recorded.
+    unrecorded: Option<LogIndex>,
+    commit_index: LogIndex,
+    last_applied: LogIndex,
+    next_index: HashMap<String, LogIndex>,
+    match_index: HashMap<String, LogIndex>,
+    leading: Option<Term>,
+    state_machine: S,
+}
+
+impl<S: StateMachine> Raft<S> {
+    pub fn new(cluster: Cluster, timeouts: Timeouts, state_machine: S, now: Instant) -> Self {
+        Self {
+            election: Election::new(cluster, timeouts, now),
+            log: Vec::new(),
+            recorded: (0, None),
+            unrecorded: None,
+            commit_index: 0,
+            last_applied: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            leading: None,
+            state_machine,
+        }
+    }
+
+    pub fn term(&self) -> Term {
+        self.election.term()
+    }
+
+    pub fn leader(&self) -> Option<&str> {
+        self.election.leader()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.election.is_leader()
+    }
+
+    pub fn commit_index(&self) -> LogIndex {
+        self.commit_index
+    }
+
+    pub fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+
+    /// Rebuilds the term, vote and log from a record a previous run returned
+    /// in [`Step::persist`]. Call it with every record, oldest first, before
+    /// driving Raft.
+    pub fn replay(&mut self, record: Record<S::Command>) {
+        match record {
+            Record::Vote { term, voted_for } => {
+                self.election.recover(term, voted_for.clone());
+                self.recorded = (term, voted_for);
+            }
+            Record::Entries { from, entries } => {
+                self.log.truncate(from as usize - 1);
+                self.log.extend(entries);
+            }
+        }
+    }
+
+    /// Appends `command` to the leader's log and starts replicating it.
+    /// Returns the index it will be applied at, once committed.
+    pub fn propose(&mut self, command: S::Command) -> Result<(LogIndex, StepFor<S>), NotLeader> {
+        if !self.is_leader() {
+            return Err(NotLeader {
+                leader: self.leader().map(str::to_string),
+            });
+        }
+
+        self.log.push(LogEntry {
+            term: self.term(),
+            command: Some(command),
+        });
+        let index = self.last_index();
+        self.changed(index);
+
+        let mut step = Step::default();
+        let peers: Vec<String> = self.election.cluster().peers().cloned().collect();
+        for peer in peers {
+            step.messages
+                .push((peer.clone(), self.append_entries(&peer)));
+        }
+        self.advance_commit(&mut step);
+        self.record(&mut step);
+
+        Ok((index, step))
+    }
+
+    pub fn tick(&mut self, now: Instant) -> StepFor<S> {
+        let position = self.position();
+        let step = self.election.tick(now, &position);
+
+        let mut step = self.finish(step);
+        self.record(&mut step);
+        step
+    }
+
+    pub fn on_message(
+        &mut self,
+        from: &str,
+        message: RaftMessage<S::Command>,
+        now: Instant,
+    ) -> StepFor<S> {
+        let mut step = match message {
+            RaftMessage::Election(message) => {
+                let position = self.position();
+                let step = self.election.on_message(from, message, now, &position);
+
+                self.finish(step)
+            }
+            RaftMessage::AppendEntries(body) => {
+                let before = self.election.view();
+                let mut step = Step::default();
+
+                let reply = self.on_append_entries(from, body, now, &mut step);
+                step.messages
+                    .push((from.to_string(), RaftMessage::AppendEntriesOk(reply)));
+                step.leader_change = self.election.leader_change_since(before);
+
+                step
+            }
+            RaftMessage::AppendEntriesOk(body) => {
+                let before = self.election.view();
+                let mut step = Step::default();
+
+                self.on_append_entries_ok(from, body, now, &mut step);
+                step.leader_change = self.election.leader_change_since(before);
+
+                step
+            }
+        };
+
+        self.record(&mut step);
+        step
+    }
+
+    fn on_append_entries(
+        &mut self,
+        from: &str,
+        body: AppendEntriesBody<S::Command>,
+        now: Instant,
+        step: &mut StepFor<S>,
+    ) -> AppendEntriesOkBody {
+        let rejected = |term, match_index| AppendEntriesOkBody {
+            term,
+            success: false,
+            match_index,
+        };
+
+        if !self.election.accept_leader(from, body.term, now) {
+            return rejected(self.term(), 0);
+        }
+
+        if body.prev_log_index > self.last_index()
+            || self.term_at(body.prev_log_index) != body.prev_log_term
+        {
+            let hint = self.last_index().min(body.prev_log_index.saturating_sub(1));
+            return rejected(self.term(), hint);
+        }
+
+        let mut index = body.prev_log_index;
+        for entry in body.entries {
+            index += 1;
+
+            if index <= self.last_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                self.log.truncate(index as usize - 1);
+            }
+            self.log.push(entry);
+            self.changed(index);
+        }
+
+        if body.leader_commit > self.commit_index {
+            self.commit_index = body.leader_commit.min(index);
+            self.apply(step);
+        }
+
+        AppendEntriesOkBody {
+            term: self.term(),
+            success: true,
+            match_index: index,
+        }
+    }
+
+    fn on_append_entries_ok(
+        &mut self,
+        from: &str,
+        body: AppendEntriesOkBody,
+        now: Instant,
+        step: &mut StepFor<S>,
+    ) {
+        if self.election.observe_term(body.term, now)
+            || !self.is_leader()
+            || body.term != self.term()
+        {
+            return;
+        }
+
+        self.election.acknowledge(from, now);
+
+        if body.success {
+            let matched = self.match_index.entry(from.to_string()).or_default();
+            *matched = (*matched).max(body.match_index);
+            self.next_index.insert(from.to_string(), *matched + 1);
+
+            self.advance_commit(step);
+        } else {
+            let next = self.next_index.entry(from.to_string()).or_insert(1);
+            *next = (*next - 1).min(body.match_index + 1).max(1);
+
+            step.messages
+                .push((from.to_string(), self.append_entries(from)));
+        }
+    }
+
+    /// Turns an election step into a Raft one, replacing heartbeats with log
+    /// replication.
+    fn finish(&mut self, election: election::Step<Position>) -> StepFor<S> {
+        let mut step = Step {
+            leader_change: election.leader_change,
+            ..Step::default()
+        };
+
+        if self.is_leader() && self.leading != Some(self.term()) {
+            self.lead(&mut step);
+        }
+
+        for (to, message) in election.messages {
+            let message = match message {
+                ElectionMessage::Heartbeat(_) => self.append_entries(&to),
+                message => RaftMessage::Election(message),
+            };
+            step.messages.push((to, message));
+        }
+
+        step
+    }
+
+    fn lead(&mut self, step: &mut StepFor<S>) {
+        self.leading = Some(self.term());
+        self.log.push(LogEntry {
+            term: self.term(),
+            command: None,
+        });
+        self.changed(self.last_index());
+
+        let next = self.last_index();
+        let peers: Vec<String> = self.election.cluster().peers().cloned().collect();
+        self.next_index = peers.iter().map(|p| (p.clone(), next)).collect();
+        self.match_index = peers.into_iter().map(|p| (p, 0)).collect();
+
+        self.advance_commit(step);
+    }
+
+    fn append_entries(&self, peer: &str) -> RaftMessage<S::Command> {
+        let next = self
+            .next_index
+            .get(peer)
+            .copied()
+            .unwrap_or(self.last_index() + 1);
+        let prev_log_index = next - 1;
+
+        RaftMessage::AppendEntries(AppendEntriesBody {
+            term: self.term(),
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries: self.log[prev_log_index as usize..].to_vec(),
+            leader_commit: self.commit_index,
+        })
+    }
+
+    /// Commits the highest entry from this term held by a majority, along
+    /// with everything before it.
+    fn advance_commit(&mut self, step: &mut StepFor<S>) {
+        let quorum = self.election.cluster().len() / 2 + 1;
+
+        let mut matched: Vec<LogIndex> = self.match_index.values().copied().collect();
+        matched.push(self.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let majority = matched[quorum - 1];
+        if majority > self.commit_index && self.term_at(majority) == self.term() {
+            self.commit_index = majority;
+            self.apply(step);
+        }
+    }
+
+    fn apply(&mut self, step: &mut StepFor<S>) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.last_applied as usize - 1];
+
+            if let Some(command) = &entry.command {
+                step.applied.push(Applied {
+                    index: self.last_applied,
+                    term: entry.term,
+                    output: self.state_machine.apply(command),
+                });
+            }
+        }
+    }
+
+    fn changed(&mut self, index: LogIndex) {
+        self.unrecorded = Some(self.unrecorded.map_or(index, |from| from.min(index)));
+    }
+
+    /// Records whatever changed in the term, vote or log during this step.
+    fn record(&mut self, step: &mut StepFor<S>) {
+        let vote = (self.term(), self.election.voted_for().map(str::to_string));
+        if vote != self.recorded {
+            step.persist.push(Record::Vote {
+                term: vote.0,
+                voted_for: vote.1.clone(),
+            });
+            self.recorded = vote;
+        }
+
+        if let Some(from) = self.unrecorded.take() {
+            step.persist.push(Record::Entries {
+                from,
+                entries: self.log[from as usize - 1..].to_vec(),
+            });
+        }
+    }
+
+    fn last_index(&self) -> LogIndex {
+        self.log.len() as LogIndex
+    }
+
+    fn term_at(&self, index: LogIndex) -> Term {
+        match index {
+            0 => 0,
+            i => self.log.get(i as usize - 1).map_or(0, |e| e.term),
+        }
+    }
+
+    fn position(&self) -> Position {
+        (self.term_at(self.last_index()), self.last_index())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        InitBody,
+        sim::{Network, Peer},
+    };
+
+    /// Appends every command to a list and returns its new length.
+    #[derive(Default)]
+    struct Log(Vec<u64>);
+
+    impl StateMachine for Log {
+        type Command = u64;
+        type Output = usize;
+
+        fn apply(&mut self, command: &u64) -> usize {
+            self.0.push(*command);
+            self.0.len()
+        }
+    }
+
+    struct Replica {
+        raft: Raft<Log>,
+        applied: Vec<Applied<usize>>,
+        records: Vec<Record<u64>>,
+    }
+
+    impl Replica {
+        fn absorb(&mut self, step: StepFor<Log>) -> Vec<(String, RaftMessage<u64>)> {
+            self.applied.extend(step.applied);
+            self.records.extend(step.persist);
+            step.messages
+        }
+    }
+
+    impl Peer for Replica {
+        type Message = RaftMessage<u64>;
+
+        fn tick(&mut self, now: Instant) -> Vec<(String, RaftMessage<u64>)> {
+            let step = self.raft.tick(now);
+            self.absorb(step)
+        }
+
+        fn receive(
+            &mut self,
+            from: &str,
+            message: RaftMessage<u64>,
+            now: Instant,
+        ) -> Vec<(String, RaftMessage<u64>)> {
+            let step = self.raft.on_message(from, message, now);
+            self.absorb(step)
+        }
+    }
+
+    fn network(n: usize) -> Network<Replica> {
+        let now = Instant::now();
+
+        Network::new(
+            now,
+            (1..=n).map(|i| {
+                let id = format!("n{i}");
+                let cluster = cluster(&id, n);
+                let replica = Replica {
+                    raft: Raft::new(cluster, Timeouts::default(), Log::default(), now),
+                    applied: Vec::new(),
+                    records: Vec::new(),
+                };
+                (id, replica)
+            }),
+        )
+    }
+
+    /// Runs the network, checking that no two replicas ever apply different
+    /// commands at the same index.
+    fn run(network: &mut Network<Replica>, duration: Duration) {
+        network.run(duration, |_, _| {});
+
+        let longest = network
+            .nodes
+            .values()
+            .map(|r| &r.raft.state_machine().0)
+            .max_by_key(|log| log.len())
+            .unwrap()
+            .clone();
+        for replica in network.nodes.values() {
+            let log = &replica.raft.state_machine().0;
+            assert_eq!(log[..], longest[..log.len()]);
+        }
+    }
+
+    fn leader(network: &Network<Replica>) -> String {
+        let leaders: Vec<&String> = network
+            .nodes
+            .iter()
+            .filter(|(_, r)| r.raft.is_leader())
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(leaders.len(), 1);
+        leaders[0].clone()
+    }
+
+    fn cluster(node_id: &str, n: usize) -> Cluster {
+        Cluster::from(InitBody {
+            node_id: node_id.to_string(),
+            node_ids: (1..=n).map(|i| format!("n{i}")).collect(),
+        })
+    }
+
+    /// A fresh Raft for `id`, rebuilt from the records it persisted.
+    fn restart(replica: &Replica, id: &str, n: usize, now: Instant) -> Raft<Log> {
+        let mut raft = Raft::new(cluster(id, n), Timeouts::default(), Log::default(), now);
+        for record in replica.records.iter().cloned() {
+            raft.replay(record);
+        }
+        raft
+    }
+
+    fn propose(network: &mut Network<Replica>, id: &str, command: u64) -> LogIndex {
+        network.with(id, |replica, _| {
+            let (index, step) = replica.raft.propose(command).unwrap();
+            (index, replica.absorb(step))
+        })
+    }
+
+    #[test]
+    fn test_replicates_commands() {
+        let mut network = network(3);
+        run(&mut network, Duration::from_secs(1));
+
+        let leader = leader(&network);
+        for command in 1..=3 {
+            propose(&mut network, &leader, command);
+        }
+        run(&mut network, Duration::from_secs(1));
+
+        for replica in network.nodes.values() {
+            assert_eq!(replica.raft.state_machine().0, [1, 2, 3]);
+        }
+
+        let applied = &network.nodes[&leader].applied;
+        assert_eq!(
+            applied.iter().map(|a| a.output).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_followers_refuse_proposals() {
+        let mut network = network(3);
+        run(&mut network, Duration::from_secs(1));
+
+        let leader = leader(&network);
+        let follower = network
+            .nodes
+            .keys()
+            .find(|&id| *id != leader)
+            .unwrap()
+            .clone();
+
+        let error = network
+            .nodes
+            .get_mut(&follower)
+            .unwrap()
+            .raft
+            .propose(1)
+            .unwrap_err();
+        assert_eq!(error.leader, Some(leader));
+    }
+
+    #[test]
+    fn test_minority_leader_cannot_commit() {
+        let mut network = network(5);
+        run(&mut network, Duration::from_secs(1));
+
+        let old = leader(&network);
+        let rest: Vec<String> = network
+            .nodes
+            .keys()
+            .filter(|&id| *id != old)
+            .cloned()
+            .collect();
+        let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+        network.partition(&[&[&old], &rest]);
+
+        // Proposed on the wrong side of the partition, so never committed.
+        let lost = propose(&mut network, &old, 1);
+        run(&mut network, Duration::from_secs(2));
+        assert!(network.nodes[&old].raft.commit_index() < lost);
+
+        let new = leader(&network);
+        assert_ne!(new, old);
+        propose(&mut network, &new, 2);
+        run(&mut network, Duration::from_secs(1));
+
+        network.heal();
+        run(&mut network, Duration::from_secs(3));
+
+        for replica in network.nodes.values() {
+            assert_eq!(replica.raft.state_machine().0, [2]);
+        }
+    }
+
+    #[test]
+    fn test_single_node_commits_alone() {
+        let mut network = network(1);
+        run(&mut network, Duration::from_secs(1));
+
+        propose(&mut network, "n1", 7);
+        assert_eq!(network.nodes["n1"].raft.state_machine().0, [7]);
+    }
+
+    #[test]
+    fn test_restart_recovers_term_vote_and_log() {
+        let mut network = network(3);
+        run(&mut network, Duration::from_secs(1));
+
+        let leader = leader(&network);
+        for command in 1..=3 {
+            propose(&mut network, &leader, command);
+        }
+        run(&mut network, Duration::from_secs(1));
+
+        let now = Instant::now();
+        for (id, replica) in &network.nodes {
+            let raft = restart(replica, id, 3, now);
+
+            assert_eq!(raft.term(), replica.raft.term());
+            assert_eq!(raft.election.voted_for(), replica.raft.election.voted_for());
+            assert_eq!(raft.log, replica.raft.log);
+            assert!(!raft.is_leader());
+        }
+    }
+
+    #[test]
+    fn test_restart_does_not_vote_twice() {
+        let now = Instant::now();
+        let request_vote = |term| {
+            RaftMessage::Election(ElectionMessage::RequestVote(election::RequestVoteBody {
+                term,
+                position: (0, 0),
+            }))
+        };
+        let granted = |step: &StepFor<Log>| {
+            step.messages.iter().any(|(_, message)| {
+                matches!(
+                    message,
+                    RaftMessage::Election(ElectionMessage::Vote(election::VoteBody {
+                        granted: true,
+                        ..
+                    }))
+                )
+            })
+        };
+
+        let mut replica = Replica {
+            raft: Raft::new(cluster("n1", 3), Timeouts::default(), Log::default(), now),
+            applied: Vec::new(),
+            records: Vec::new(),
+        };
+        let step = replica.raft.on_message("n2", request_vote(1), now);
+        assert!(granted(&step));
+        assert_eq!(
+            step.persist,
+            [Record::Vote {
+                term: 1,
+                voted_for: Some("n2".to_string()),
+            }]
+        );
+        replica.absorb(step);
+
+        let mut raft = restart(&replica, "n1", 3, now);
+        assert!(!granted(&raft.on_message("n3", request_vote(1), now)));
+        assert!(granted(&raft.on_message("n2", request_vote(1), now)));
+    }
+}
@@ -0,0 +1,110 @@
+//! A simulated network for testing peer protocols without Maelstrom: a
+//! virtual clock, fixed latency and partitions.
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+const STEP: Duration = Duration::from_millis(5);
+const LATENCY: Duration = Duration::from_millis(10);
+
+pub(crate) trait Peer {
+    type Message;
+
+    fn tick(&mut self, now: Instant) -> Vec<(String, Self::Message)>;
+
+    fn receive(
+        &mut self,
+        from: &str,
+        message: Self::Message,
+        now: Instant,
+    ) -> Vec<(String, Self::Message)>;
+}
+
+pub(crate) struct Network<P: Peer> {
+    pub now: Instant,
+    pub nodes: BTreeMap<String, P>,
+    in_flight: VecDeque<(Instant, String, String, P::Message)>,
+    /// Nodes only hear from others in the same group; empty when the network
+    /// is whole.
+    groups: Vec<HashSet<String>>,
+}
+
+impl<P: Peer> Network<P> {
+    pub fn new(now: Instant, nodes: impl IntoIterator<Item = (String, P)>) -> Self {
+        Self {
+            now,
+            nodes: nodes.into_iter().collect(),
+            in_flight: VecDeque::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        self.groups = groups
+            .iter()
+            .map(|g| g.iter().map(|id| id.to_string()).collect())
+            .collect();
+    }
+
+    pub fn heal(&mut self) {
+        self.groups.clear();
+    }
+
+    fn connected(&self, a: &str, b: &str) -> bool {
+        self.groups.is_empty() || self.groups.iter().any(|g| g.contains(a) && g.contains(b))
+    }
+
+    /// Advances the clock by `duration`, delivering messages and ticking
+    /// every node, and calls `check` on each node after it handles anything.
+    pub fn run(&mut self, duration: Duration, mut check: impl FnMut(&str, &P)) {
+        let until = self.now + duration;
+
+        while self.now < until {
+            self.now += STEP;
+
+            while self
+                .in_flight
+                .front()
+                .is_some_and(|(at, ..)| *at <= self.now)
+            {
+                let (_, from, to, message) = self.in_flight.pop_front().unwrap();
+                if !self.connected(&from, &to) {
+                    continue;
+                }
+
+                let node = self.nodes.get_mut(&to).unwrap();
+                let sent = node.receive(&from, message, self.now);
+                check(&to, node);
+                self.send(&to, sent);
+            }
+
+            let ids: Vec<String> = self.nodes.keys().cloned().collect();
+            for id in ids {
+                let node = self.nodes.get_mut(&id).unwrap();
+                let sent = node.tick(self.now);
+                check(&id, node);
+                self.send(&id, sent);
+            }
+        }
+    }
+
+    /// Hands `f` a node to poke directly, delivering whatever it sends.
+    pub fn with<T>(
+        &mut self,
+        id: &str,
+        f: impl FnOnce(&mut P, Instant) -> (T, Vec<(String, P::Message)>),
+    ) -> T {
+        let (result, sent) = f(self.nodes.get_mut(id).unwrap(), self.now);
+        self.send(id, sent);
+        result
+    }
+
+    fn send(&mut self, from: &str, messages: Vec<(String, P::Message)>) {
+        for (to, message) in messages {
+            self.in_flight
+                .push_back((self.now + LATENCY, from.to_string(), to, message));
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+/// Directory holding each node's write-ahead log. Storage is disabled when
+/// unset.
+pub const DATA_DIR_VAR: &str = "GOSSIP_GLOMERS_DATA_DIR";
+
+/// One of `always`, `event` or `never`; see [`FsyncPolicy`].
+pub const FSYNC_VAR: &str = "GOSSIP_GLOMERS_FSYNC";
+
+/// How many log records to accumulate before snapshotting the node.
+pub const SNAPSHOT_EVERY_VAR: &str = "GOSSIP_GLOMERS_SNAPSHOT_EVERY";
+
+const DEFAULT_SNAPSHOT_EVERY: usize = 1000;
+
+// Every record is framed as a little-endian u32 payload length followed by
+// a CRC32 over the length and the payload.
+const HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FsyncPolicy {
+    /// fsync after every append.
+    Always,
+    /// fsync once per event, before any of the event's output is written, so
+    /// a reply is never sent for a mutation that could still be lost.
+    #[default]
+    EveryEvent,
+    /// Leave flushing to the OS. Survives the process being killed, but not
+    /// the machine going down.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "event" => Ok(FsyncPolicy::EveryEvent),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => anyhow::bail!("unknown fsync policy {s:?}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    pub snapshot_every: usize,
+}
+
+impl Options {
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(dir) = env::var_os(DATA_DIR_VAR) else {
+            return Ok(None);
+        };
+
+        let fsync = match env::var(FSYNC_VAR) {
+            Ok(policy) => policy.parse()?,
+            Err(env::VarError::NotPresent) => FsyncPolicy::default(),
+            Err(e) => return Err(e).context(FSYNC_VAR),
+        };
+
+        let snapshot_every = match env::var(SNAPSHOT_EVERY_VAR) {
+            Ok(every) => every.parse().context(SNAPSHOT_EVERY_VAR)?,
+            Err(env::VarError::NotPresent) => DEFAULT_SNAPSHOT_EVERY,
+            Err(e) => return Err(e).context(SNAPSHOT_EVERY_VAR),
+        };
+
+        Ok(Some(Self {
+            dir: dir.into(),
+            fsync,
+            snapshot_every,
+        }))
+    }
+}
+
+/// What a node left behind before it last stopped.
+#[derive(Debug, Default, PartialEq)]
+pub struct Recovery {
+    pub snapshot: Option<Vec<u8>>,
+    pub records: Vec<Vec<u8>>,
+}
+
+/// A node's snapshot plus the log of records appended since it was taken.
+///
+/// Each snapshot starts a new log generation: the snapshot is written
+/// alongside the old log, renamed into place, and only then is the old log
+/// removed. A crash at any point leaves either the old snapshot with its full
+/// log or the new snapshot with an empty one.
+pub struct Store {
+    dir: PathBuf,
+    node_id: String,
+    fsync: FsyncPolicy,
+    snapshot_every: usize,
+    generation: u64,
+    wal: Wal,
+    appended: usize,
+}
+
+impl Store {
+    pub fn open(options: &Options, node_id: &str) -> anyhow::Result<(Self, Recovery)> {
+        fs::create_dir_all(&options.dir)
+            .with_context(|| format!("creating {}", options.dir.display()))?;
+
+        let snapshot_path = options.dir.join(format!("{node_id}.snapshot"));
+        let (generation, snapshot) = match fs::read(&snapshot_path) {
+            Ok(bytes) => {
+                let (mut records, _) = decode(&bytes);
+                let Some(mut snapshot) = records.pop().filter(|s| s.len() >= 8) else {
+                    anyhow::bail!("corrupt snapshot {}", snapshot_path.display());
+                };
+                let state = snapshot.split_off(8);
+                (
+                    u64::from_le_bytes(snapshot.try_into().unwrap()),
+                    Some(state),
+                )
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading {}", snapshot_path.display()));
+            }
+        };
+
+        if let Some(previous) = generation.checked_sub(1) {
+            let stale = log_path(&options.dir, node_id, previous);
+            match fs::remove_file(&stale) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("removing {}", stale.display()));
+                }
+                _ => {}
+            }
+        }
+
+        let (wal, records) =
+            Wal::open(&log_path(&options.dir, node_id, generation), options.fsync)?;
+
+        Ok((
+            Self {
+                dir: options.dir.clone(),
+                node_id: node_id.to_string(),
+                fsync: options.fsync,
+                snapshot_every: options.snapshot_every,
+                generation,
+                appended: records.len(),
+                wal,
+            },
+            Recovery { snapshot, records },
+        ))
+    }
+
+    pub fn append(&mut self, record: &[u8]) -> anyhow::Result<()> {
+        self.wal.append(record)?;
+        self.appended += 1;
+
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> anyhow::Result<()> {
+        self.wal.sync()
+    }
+
+    /// Whether enough has been logged since the last snapshot to take another.
+    pub fn snapshot_due(&self) -> bool {
+        self.appended >= self.snapshot_every
+    }
+
+    /// Replaces the snapshot with `state` and starts a fresh, empty log.
+    pub fn snapshot(&mut self, state: &[u8]) -> anyhow::Result<()> {
+        let generation = self.generation + 1;
+
+        let mut payload = Vec::with_capacity(8 + state.len());
+        payload.extend_from_slice(&generation.to_le_bytes());
+        payload.extend_from_slice(state);
+
+        let tmp = self.dir.join(format!("{}.snapshot.tmp", self.node_id));
+        let mut file = File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+        file.write_all(&frame(&payload)?)
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        file.sync_all().context("syncing snapshot")?;
+
+        fs::rename(&tmp, self.dir.join(format!("{}.snapshot", self.node_id)))
+            .context("installing snapshot")?;
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .context("syncing data directory")?;
+
+        let (wal, _) = Wal::open(&log_path(&self.dir, &self.node_id, generation), self.fsync)?;
+        let stale = log_path(&self.dir, &self.node_id, self.generation);
+
+        self.wal = wal;
+        self.generation = generation;
+        self.appended = 0;
+
+        fs::remove_file(&stale).with_context(|| format!("removing {}", stale.display()))
+    }
+
+    /// Puts off the next snapshot for another full interval, for nodes that
+    /// have nothing to snapshot.
+    pub fn defer_snapshot(&mut self) {
+        self.appended = 0;
+    }
+}
+
+fn log_path(dir: &Path, node_id: &str, generation: u64) -> PathBuf {
+    dir.join(format!("{node_id}.{generation}.wal"))
+}
+
+/// An append-only log of opaque, checksummed records.
+pub struct Wal {
+    file: File,
+    fsync: FsyncPolicy,
+    dirty: bool,
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path` and returns every intact record
+    /// in the order it was appended.
+    ///
+    /// Replay stops at the first short or corrupt record, which is what a
+    /// crash in the middle of an append leaves behind; the log is cut back to
+    /// the last good record so new appends follow on from it.
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> anyhow::Result<(Self, Vec<Vec<u8>>)> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .with_context(|| format!("reading {}", path.display()))?;
+
+        let (records, valid) = decode(&bytes);
+        if valid < bytes.len() {
+            file.set_len(valid as u64)
+                .context("truncating torn write-ahead log tail")?;
+            file.sync_data().context("syncing write-ahead log")?;
+        }
+
+        Ok((
+            Self {
+                file,
+                fsync,
+                dirty: false,
+            },
+            records,
+        ))
+    }
+
+    pub fn append(&mut self, record: &[u8]) -> anyhow::Result<()> {
+        self.file
+            .write_all(&frame(record)?)
+            .context("appending to write-ahead log")?;
+
+        if self.fsync == FsyncPolicy::Always {
+            self.file.sync_data().context("syncing write-ahead log")?;
+        } else {
+            self.dirty = true;
+        }
+
+        Ok(())
+    }
+
+    /// Makes everything appended so far durable, as far as the policy asks.
+    pub fn sync(&mut self) -> anyhow::Result<()> {
+        if self.dirty && self.fsync == FsyncPolicy::EveryEvent {
+            self.file.sync_data().context("syncing write-ahead log")?;
+        }
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+fn frame(record: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let len = u32::try_from(record.len()).context("record too large")?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+    hasher.update(record);
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + record.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&hasher.finalize().to_le_bytes());
+    frame.extend_from_slice(record);
+
+    Ok(frame)
+}
+
+/// Splits `bytes` into records, returning them along with the length of the
+/// valid prefix.
+fn decode(bytes: &[u8]) -> (Vec<Vec<u8>>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while let Some(header) = bytes.get(offset..offset + HEADER_LEN) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+        let start = offset + HEADER_LEN;
+        let Some(record) = bytes.get(start..start + len as usize) else {
+            break;
+        };
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&len.to_le_bytes());
+        hasher.update(record);
+        if hasher.finalize() != checksum {
+            break;
+        }
+
+        records.push(record.to_vec());
+        offset = start + record.len();
+    }
+
+    (records, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = env::temp_dir()
+            .join(format!("gossip-glomers-{}", std::process::id()))
+            .join(format!("{name}.wal"));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_replays_appended_records() {
+        let path = temp_log("replay");
+
+        let (mut wal, records) = Wal::open(&path, FsyncPolicy::EveryEvent).unwrap();
+        assert!(records.is_empty());
+        wal.append(b"1").unwrap();
+        wal.append(b"").unwrap();
+        wal.append(b"three").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, FsyncPolicy::EveryEvent).unwrap();
+        assert_eq!(records, vec![b"1".to_vec(), vec![], b"three".to_vec()]);
+    }
+
+    #[test]
+    fn test_drops_torn_tail() {
+        let path = temp_log("torn");
+
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        wal.append(b"kept").unwrap();
+        wal.append(b"torn").unwrap();
+        drop(wal);
+
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+
+        let (mut wal, records) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records, vec![b"kept".to_vec()]);
+        wal.append(b"after").unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records, vec![b"kept".to_vec(), b"after".to_vec()]);
+    }
+
+    #[test]
+    fn test_stops_at_corrupt_record() {
+        let path = temp_log("corrupt");
+
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        wal.append(b"good").unwrap();
+        wal.append(b"flipped").unwrap();
+        wal.append(b"unreachable").unwrap();
+        drop(wal);
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[2 * HEADER_LEN + 4] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let (_, records) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(records, vec![b"good".to_vec()]);
+    }
+
+    fn options(name: &str, snapshot_every: usize) -> Options {
+        let dir = env::temp_dir()
+            .join(format!("gossip-glomers-{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+
+        Options {
+            dir,
+            fsync: FsyncPolicy::EveryEvent,
+            snapshot_every,
+        }
+    }
+
+    #[test]
+    fn test_store_snapshot_truncates_log() {
+        let options = options("snapshot", 2);
+
+        let (mut store, recovery) = Store::open(&options, "n1").unwrap();
+        assert_eq!(recovery, Recovery::default());
+
+        store.append(b"1").unwrap();
+        assert!(!store.snapshot_due());
+        store.append(b"2").unwrap();
+        assert!(store.snapshot_due());
+
+        store.snapshot(b"1,2").unwrap();
+        assert!(!store.snapshot_due());
+        store.append(b"3").unwrap();
+        store.sync().unwrap();
+        drop(store);
+
+        assert!(!log_path(&options.dir, "n1", 0).exists());
+
+        let (store, recovery) = Store::open(&options, "n1").unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                snapshot: Some(b"1,2".to_vec()),
+                records: vec![b"3".to_vec()],
+            }
+        );
+        assert_eq!(store.generation, 1);
+    }
+
+    #[test]
+    fn test_store_recovers_from_crash_before_log_rotation() {
+        let options = options("rotation", 1);
+
+        let (mut store, _) = Store::open(&options, "n1").unwrap();
+        store.append(b"1").unwrap();
+        store.snapshot(b"1").unwrap();
+        drop(store);
+
+        // As if the node died after installing the snapshot but before it
+        // removed the previous generation's log.
+        let (mut stale, _) =
+            Wal::open(&log_path(&options.dir, "n1", 0), FsyncPolicy::Never).unwrap();
+        stale.append(b"1").unwrap();
+        drop(stale);
+
+        let (_, recovery) = Store::open(&options, "n1").unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                snapshot: Some(b"1".to_vec()),
+                records: vec![],
+            }
+        );
+        assert!(!log_path(&options.dir, "n1", 0).exists());
+    }
+
+    #[test]
+    fn test_parse_fsync_policy() {
+        assert_eq!(
+            "always".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Always
+        );
+        assert_eq!(
+            "event".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::EveryEvent
+        );
+        assert_eq!("never".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Never);
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}
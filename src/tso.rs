@@ -0,0 +1,205 @@
+//! Maelstrom's `lin-tso` service, a linearizable timestamp oracle: every
+//! timestamp it hands out is greater than any it handed out before.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Message, MessageBody, MessageID, MessageIds, Response,
+    kv::{ErrorBody, KvError},
+};
+
+pub const SERVICE: &str = "lin-tso";
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageType {
+    Ts,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct TsOkBody {
+    pub ts: u64,
+}
+
+/// Errors share their codes with the key-value services, so they decode
+/// the same way.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseType {
+    TsOk(TsOkBody),
+    Error(ErrorBody),
+}
+
+/// A timestamp request the client has stopped waiting for.
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub msg_id: MessageID,
+    pub result: Result<u64, KvError>,
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Requests timestamps and matches the replies back to them by `msg_id`.
+pub struct Client {
+    node_id: String,
+    ids: MessageIds,
+    timeout: Duration,
+    pending: HashMap<MessageID, Instant>,
+}
+
+impl Client {
+    /// Numbers requests from `ids`, shared with the node's other clients so
+    /// a reply from one service never matches another's request.
+    pub fn new(node_id: String, ids: MessageIds) -> Self {
+        Self {
+            node_id,
+            ids,
+            timeout: DEFAULT_TIMEOUT,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// How long to wait for a reply before [`Client::expire`] gives up on it.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn timestamp(&mut self) -> Message<MessageType> {
+        let msg_id = self.ids.next();
+        self.pending.insert(msg_id, Instant::now() + self.timeout);
+
+        Message {
+            src: self.node_id.clone(),
+            dst: SERVICE.to_string(),
+            body: MessageBody {
+                kind: MessageType::Ts,
+                msg_id: Some(msg_id),
+            },
+        }
+    }
+
+    /// Matches a reply to its request. `None` for replies to requests that
+    /// were never sent or have already expired.
+    pub fn resolve(&mut self, response: Response<ResponseType>) -> Option<Outcome> {
+        let msg_id = response.body.in_reply_to?;
+        self.pending.remove(&msg_id)?;
+
+        let result = match response.body.kind {
+            ResponseType::TsOk(body) => Ok(body.ts),
+            ResponseType::Error(error) => Err(error.into()),
+        };
+
+        Some(Outcome { msg_id, result })
+    }
+
+    /// Gives up on every request that has gone unanswered past the timeout.
+    pub fn expire(&mut self, now: Instant) -> Vec<Outcome> {
+        let mut expired: Vec<MessageID> = self
+            .pending
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(&msg_id, _)| msg_id)
+            .collect();
+        expired.sort_unstable();
+
+        for msg_id in &expired {
+            self.pending.remove(msg_id);
+        }
+
+        expired
+            .into_iter()
+            .map(|msg_id| Outcome {
+                msg_id,
+                result: Err(KvError::Timeout),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reply(in_reply_to: MessageID, kind: ResponseType) -> Response<ResponseType> {
+        Response {
+            src: SERVICE.to_string(),
+            dst: "n1".to_string(),
+            body: crate::ResponseBody {
+                kind,
+                msg_id: None,
+                in_reply_to: Some(in_reply_to),
+            },
+        }
+    }
+
+    #[test]
+    fn test_matches_maelstrom() {
+        let mut client = Client::new("n1".to_string(), MessageIds::default());
+
+        assert_eq!(
+            serde_json::to_value(client.timestamp()).unwrap(),
+            json!({
+                "src": "n1",
+                "dest": "lin-tso",
+                "body": {"type": "ts", "msg_id": 1},
+            })
+        );
+
+        let body: crate::ResponseBody<ResponseType> =
+            serde_json::from_value(json!({"type": "ts_ok", "ts": 42, "in_reply_to": 1})).unwrap();
+        assert_eq!(body.kind, ResponseType::TsOk(TsOkBody { ts: 42 }));
+    }
+
+    #[test]
+    fn test_shares_message_ids_with_kv() {
+        let ids = MessageIds::default();
+        let mut tso = Client::new("n1".to_string(), ids.clone());
+        let mut kv = crate::kv::Linearizable::new("n1".to_string(), ids);
+
+        let ts = tso.timestamp().body.msg_id;
+        let read = kv.read::<u64>("k").body.msg_id;
+        assert_ne!(ts, read);
+
+        // The kv reply must not resolve the timestamp request.
+        assert_eq!(
+            tso.resolve(reply(read.unwrap(), ResponseType::TsOk(TsOkBody { ts: 1 }))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolve_and_expire() {
+        let mut client = Client::new("n1".to_string(), MessageIds::default())
+            .with_timeout(Duration::from_millis(100));
+        let first = client.timestamp().body.msg_id.unwrap();
+        let second = client.timestamp().body.msg_id.unwrap();
+
+        assert_eq!(
+            client.resolve(reply(first, ResponseType::TsOk(TsOkBody { ts: 7 }))),
+            Some(Outcome {
+                msg_id: first,
+                result: Ok(7),
+            })
+        );
+        assert_eq!(
+            client.resolve(reply(first, ResponseType::TsOk(TsOkBody { ts: 8 }))),
+            None
+        );
+
+        assert!(client.expire(Instant::now()).is_empty());
+        assert_eq!(
+            client.expire(Instant::now() + Duration::from_millis(100)),
+            vec![Outcome {
+                msg_id: second,
+                result: Err(KvError::Timeout),
+            }]
+        );
+    }
+}
@@ -0,0 +1,536 @@
+//! Multi-key transactions on top of `lin-kv`, which only compare-and-swaps
+//! one key at a time.
+//!
+//! Every value lives in an immutable blob under a key of its own, and a
+//! single root key maps each transaction key to the blob holding its current
+//! value. A transaction reads the root, reads the blobs it needs, writes new
+//! blobs for what it changes and then swaps in a new root. If another
+//! transaction swapped the root first, it aborts; its blobs are left
+//! unreferenced.
+//!
+//! Blobs never change once written, so they are cached for as long as the
+//! latest root this node has seen, or a running transaction, points at
+//! them, and a read-only transaction commits as soon as it has read.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    Message, MessageID,
+    kv::{self, KvClient, KvError, LinKv, Outcome, Reply},
+};
+
+pub type Key = u64;
+
+pub type Value = u64;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Function {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+/// One micro-operation of a Maelstrom `txn`, `["r", key, null]` or
+/// `["w", key, value]`. Reads come back with the value filled in.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Op(pub Function, pub Key, pub Option<Value>);
+
+/// Where the root points: the blob holding each key's value.
+///
+/// Every commit swaps in a root one version past the one it read, so of two
+/// roots the one with the higher version is the later.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+struct Root {
+    version: u64,
+    keys: BTreeMap<Key, String>,
+}
+
+const ROOT: &str = "root";
+
+pub type TxnId = u64;
+
+/// A finished transaction: its ops with reads filled in, or why it aborted.
+/// [`KvError::PreconditionFailed`] means another transaction committed
+/// first, which Maelstrom calls a conflict,
+/// [`crate::ErrorBody::TXN_CONFLICT`].
+#[derive(Debug, PartialEq)]
+pub struct Committed {
+    pub id: TxnId,
+    pub result: Result<Vec<Op>, KvError>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    Send(Vec<Message<kv::MessageType<serde_json::Value>>>),
+    /// More replies are due before the next step.
+    Waiting,
+    Done(Committed),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Step {
+    ReadingRoot,
+    ReadingBlobs,
+    WritingBlobs,
+    SwappingRoot,
+}
+
+struct Txn {
+    ops: Vec<Op>,
+    /// The root as read, and as it will be if the transaction commits.
+    root: Root,
+    new_root: Root,
+    step: Step,
+    /// Requests outstanding in the current step.
+    waiting: usize,
+}
+
+/// Runs transactions for the `txn` workloads. Only a `lin-kv` client will
+/// do: on a service that may serve a stale root, transactions could read
+/// from and overwrite an old snapshot.
+pub struct Transactions {
+    next_id: TxnId,
+    running: HashMap<TxnId, Txn>,
+    requests: HashMap<MessageID, TxnId>,
+    /// The latest root this node has read or swapped in. Replies may arrive
+    /// out of order, so an older root read later never replaces it.
+    current: Root,
+    blobs: HashMap<String, Value>,
+}
+
+impl Default for Transactions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transactions {
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            running: HashMap::new(),
+            requests: HashMap::new(),
+            current: Root::default(),
+            blobs: HashMap::new(),
+        }
+    }
+
+    /// Starts a transaction, beginning with a read of the root.
+    pub fn txn<K: KvClient<Consistency = LinKv>>(
+        &mut self,
+        client: &mut K,
+        ops: Vec<Op>,
+    ) -> (TxnId, Message<kv::MessageType<serde_json::Value>>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.running.insert(
+            id,
+            Txn {
+                ops,
+                root: Root::default(),
+                new_root: Root::default(),
+                step: Step::ReadingRoot,
+                waiting: 1,
+            },
+        );
+
+        let message = client.read(ROOT);
+        self.track(&message, id);
+        (id, message)
+    }
+
+    /// Whether `msg_id` is a request one of the transactions is waiting on.
+    pub fn owns(&self, msg_id: MessageID) -> bool {
+        self.requests.contains_key(&msg_id)
+    }
+
+    /// Takes the next step for the transaction `outcome` belongs to. `None`
+    /// if it belongs to none.
+    pub fn on_outcome<K: KvClient<Consistency = LinKv>>(
+        &mut self,
+        client: &mut K,
+        outcome: Outcome<serde_json::Value>,
+    ) -> Option<Progress> {
+        let id = self.requests.remove(&outcome.msg_id)?;
+        let txn = self.running.get_mut(&id)?;
+        txn.waiting -= 1;
+
+        let progress = match (txn.step, outcome.result) {
+            (Step::ReadingRoot, Ok(Reply::Read(root))) => match serde_json::from_value(root) {
+                Ok(root) => {
+                    txn.root = root;
+                    if txn.root.version > self.current.version {
+                        self.current = txn.root.clone();
+                    }
+                    self.read_blobs(client, id)
+                }
+                Err(e) => self.finish(id, Err(KvError::Decode(e.to_string()))),
+            },
+            // Nothing has committed yet.
+            (Step::ReadingRoot, Err(KvError::KeyDoesNotExist)) => self.read_blobs(client, id),
+            (Step::ReadingBlobs, Ok(Reply::Read(value))) => match serde_json::from_value(value) {
+                Ok(value) => {
+                    self.blobs.insert(outcome.key, value);
+                    self.read_blobs(client, id)
+                }
+                Err(e) => self.finish(id, Err(KvError::Decode(e.to_string()))),
+            },
+            (Step::WritingBlobs, Ok(Reply::Written)) => self.swap_root(client, id),
+            (Step::SwappingRoot, Ok(Reply::Swapped)) => {
+                if txn.new_root.version > self.current.version {
+                    self.current = txn.new_root.clone();
+                }
+                let ops = self.apply(id);
+                self.finish(id, Ok(ops))
+            }
+            (_, Err(error)) => self.finish(id, Err(error)),
+            (_, Ok(_)) => self.finish(
+                id,
+                Err(KvError::Decode("reply does not match request".to_string())),
+            ),
+        };
+
+        Some(progress)
+    }
+
+    /// Reads whatever blobs are still missing, then moves on to writing.
+    fn read_blobs<K: KvClient<Consistency = LinKv>>(
+        &mut self,
+        client: &mut K,
+        id: TxnId,
+    ) -> Progress {
+        let txn = self.running.get_mut(&id).expect("transaction is running");
+        if txn.waiting > 0 {
+            return Progress::Waiting;
+        }
+
+        let missing: Vec<String> = txn
+            .ops
+            .iter()
+            .filter(|Op(f, ..)| *f == Function::Read)
+            .filter_map(|Op(_, key, _)| txn.root.keys.get(key))
+            .filter(|blob| !self.blobs.contains_key(*blob))
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if !missing.is_empty() {
+            txn.step = Step::ReadingBlobs;
+            txn.waiting = missing.len();
+            let messages: Vec<_> = missing.iter().map(|blob| client.read(blob)).collect();
+            for message in &messages {
+                self.track(message, id);
+            }
+            return Progress::Send(messages);
+        }
+
+        self.write_blobs(client, id)
+    }
+
+    fn write_blobs<K: KvClient<Consistency = LinKv>>(
+        &mut self,
+        client: &mut K,
+        id: TxnId,
+    ) -> Progress {
+        let txn = self.running.get_mut(&id).expect("transaction is running");
+
+        let mut writes = BTreeMap::new();
+        for Op(f, key, value) in &txn.ops {
+            if *f == Function::Write {
+                writes.insert(*key, value.unwrap_or_default());
+            }
+        }
+        if writes.is_empty() {
+            let ops = self.apply(id);
+            return self.finish(id, Ok(ops));
+        }
+
+        let mut root = Root {
+            version: txn.root.version + 1,
+            keys: txn.root.keys.clone(),
+        };
+        let mut messages = Vec::new();
+        for (key, value) in writes {
+            let blob = Uuid::now_v7().to_string();
+            messages.push(client.write(&blob, serde_json::json!(value)));
+            self.blobs.insert(blob.clone(), value);
+            root.keys.insert(key, blob);
+        }
+
+        txn.new_root = root;
+        txn.step = Step::WritingBlobs;
+        txn.waiting = messages.len();
+        for message in &messages {
+            self.track(message, id);
+        }
+        Progress::Send(messages)
+    }
+
+    fn swap_root<K: KvClient<Consistency = LinKv>>(
+        &mut self,
+        client: &mut K,
+        id: TxnId,
+    ) -> Progress {
+        let txn = self.running.get_mut(&id).expect("transaction is running");
+        if txn.waiting > 0 {
+            return Progress::Waiting;
+        }
+        txn.step = Step::SwappingRoot;
+
+        let message = client.compare_and_swap(
+            ROOT,
+            serde_json::json!(txn.root),
+            serde_json::json!(txn.new_root),
+            true,
+        );
+        txn.waiting = 1;
+        self.track(&message, id);
+        Progress::Send(vec![message])
+    }
+
+    /// The transaction's ops with reads filled in from the root it read and
+    /// its own earlier writes.
+    fn apply(&self, id: TxnId) -> Vec<Op> {
+        let txn = &self.running[&id];
+        let mut written = HashMap::new();
+
+        txn.ops
+            .iter()
+            .map(|Op(f, key, value)| match f {
+                Function::Read => {
+                    let value = written.get(key).copied().or_else(|| {
+                        let blob = txn.root.keys.get(key)?;
+                        self.blobs.get(blob).copied()
+                    });
+                    Op(Function::Read, *key, value)
+                }
+                Function::Write => {
+                    written.insert(*key, value.unwrap_or_default());
+                    Op(Function::Write, *key, *value)
+                }
+            })
+            .collect()
+    }
+
+    fn finish(&mut self, id: TxnId, result: Result<Vec<Op>, KvError>) -> Progress {
+        self.running.remove(&id);
+        self.requests.retain(|_, txn| *txn != id);
+        self.evict();
+        Progress::Done(Committed { id, result })
+    }
+
+    /// Drops the blobs nothing can read any more: overwritten values, and
+    /// those of transactions that aborted. A root this node has not seen
+    /// may still point at one, in which case it is read again.
+    fn evict(&mut self) {
+        let live: HashSet<&String> = self
+            .running
+            .values()
+            .flat_map(|txn| txn.root.keys.values().chain(txn.new_root.keys.values()))
+            .chain(self.current.keys.values())
+            .collect();
+        self.blobs.retain(|blob, _| live.contains(blob));
+    }
+
+    fn track<T>(&mut self, message: &Message<kv::MessageType<T>>, id: TxnId) {
+        self.requests
+            .insert(message.body.msg_id.expect("client sets msg_id"), id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::{Memory, MemoryStore};
+
+    /// Hands every reply back until the transactions stop asking for more.
+    fn run(txns: &mut Transactions, client: &mut Memory<LinKv>) -> Vec<Committed> {
+        let mut committed = Vec::new();
+        loop {
+            let replies = client.replies();
+            if replies.is_empty() {
+                return committed;
+            }
+
+            for response in replies {
+                let outcome = client.resolve(response).unwrap();
+                if let Some(Progress::Done(done)) = txns.on_outcome(client, outcome) {
+                    committed.push(done);
+                }
+            }
+        }
+    }
+
+    fn op(f: Function, key: Key, value: Option<Value>) -> Op {
+        Op(f, key, value)
+    }
+
+    #[test]
+    fn test_commits_and_reads_back() {
+        let store = MemoryStore::default();
+        let mut client = Memory::new("n1".to_string(), &store);
+        let mut txns = Transactions::new();
+
+        let (id, _) = txns.txn(
+            &mut client,
+            vec![
+                op(Function::Read, 1, None),
+                op(Function::Write, 1, Some(5)),
+                op(Function::Read, 1, None),
+                op(Function::Write, 2, Some(6)),
+            ],
+        );
+        assert_eq!(
+            run(&mut txns, &mut client),
+            vec![Committed {
+                id,
+                result: Ok(vec![
+                    op(Function::Read, 1, None),
+                    op(Function::Write, 1, Some(5)),
+                    op(Function::Read, 1, Some(5)),
+                    op(Function::Write, 2, Some(6)),
+                ]),
+            }]
+        );
+
+        // A fresh node has none of the blobs cached.
+        let mut client = Memory::new("n2".to_string(), &store);
+        let mut txns = Transactions::new();
+        let (id, _) = txns.txn(
+            &mut client,
+            vec![op(Function::Read, 2, None), op(Function::Read, 3, None)],
+        );
+        assert_eq!(
+            run(&mut txns, &mut client),
+            vec![Committed {
+                id,
+                result: Ok(vec![
+                    op(Function::Read, 2, Some(6)),
+                    op(Function::Read, 3, None)
+                ]),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_concurrent_writer_aborts() {
+        let store = MemoryStore::default();
+        let mut client = Memory::new("n1".to_string(), &store);
+        let mut txns = Transactions::new();
+
+        // Both read the same (missing) root before either swaps it.
+        let (first, _) = txns.txn(&mut client, vec![op(Function::Write, 1, Some(1))]);
+        let (second, _) = txns.txn(&mut client, vec![op(Function::Write, 1, Some(2))]);
+
+        let mut committed = run(&mut txns, &mut client);
+        committed.sort_by_key(|c| c.id);
+        assert_eq!(
+            committed,
+            vec![
+                Committed {
+                    id: first,
+                    result: Ok(vec![op(Function::Write, 1, Some(1))]),
+                },
+                Committed {
+                    id: second,
+                    result: Err(KvError::PreconditionFailed),
+                },
+            ]
+        );
+
+        let (id, _) = txns.txn(&mut client, vec![op(Function::Read, 1, None)]);
+        assert_eq!(
+            run(&mut txns, &mut client),
+            vec![Committed {
+                id,
+                result: Ok(vec![op(Function::Read, 1, Some(1))]),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_evicts_unreferenced_blobs() {
+        let store = MemoryStore::default();
+        let mut client = Memory::new("n1".to_string(), &store);
+        let mut txns = Transactions::new();
+
+        for value in 1..=3 {
+            txns.txn(
+                &mut client,
+                vec![
+                    op(Function::Write, 1, Some(value)),
+                    op(Function::Write, 2, Some(value)),
+                ],
+            );
+            run(&mut txns, &mut client);
+        }
+        assert_eq!(txns.blobs.len(), 2);
+
+        // An aborted transaction's blobs go too.
+        txns.txn(&mut client, vec![op(Function::Write, 1, Some(4))]);
+        txns.txn(&mut client, vec![op(Function::Write, 1, Some(5))]);
+        run(&mut txns, &mut client);
+        assert_eq!(txns.blobs.len(), 2);
+    }
+
+    #[test]
+    fn test_keeps_the_newest_root_whatever_order_replies_arrive_in() {
+        let store = MemoryStore::default();
+        let mut client = Memory::new("n1".to_string(), &store);
+        let mut txns = Transactions::new();
+        txns.txn(&mut client, vec![op(Function::Write, 1, Some(1))]);
+        run(&mut txns, &mut client);
+
+        // n1 reads the root once before n2 commits and once after.
+        let (older, _) = txns.txn(&mut client, vec![op(Function::Read, 1, None)]);
+        let mut other = Memory::new("n2".to_string(), &store);
+        let mut other_txns = Transactions::new();
+        other_txns.txn(&mut other, vec![op(Function::Write, 1, Some(2))]);
+        run(&mut other_txns, &mut other);
+        let (newer, _) = txns.txn(&mut client, vec![op(Function::Read, 1, None)]);
+
+        let mut committed = Vec::new();
+        for response in client.replies().into_iter().rev() {
+            let outcome = client.resolve(response).unwrap();
+            if let Some(Progress::Done(done)) = txns.on_outcome(&mut client, outcome) {
+                committed.push(done);
+            }
+        }
+        committed.extend(run(&mut txns, &mut client));
+        committed.sort_by_key(|c| c.id);
+
+        assert_eq!(
+            committed,
+            vec![
+                Committed {
+                    id: older,
+                    result: Ok(vec![op(Function::Read, 1, Some(1))]),
+                },
+                Committed {
+                    id: newer,
+                    result: Ok(vec![op(Function::Read, 1, Some(2))]),
+                },
+            ]
+        );
+        assert_eq!(txns.current.version, 2);
+        assert_eq!(txns.blobs.values().collect::<Vec<_>>(), [&2]);
+    }
+
+    #[test]
+    fn test_ops_match_maelstrom() {
+        let ops: Vec<Op> = serde_json::from_str(r#"[["r", 1, null], ["w", 2, 3]]"#).unwrap();
+        assert_eq!(
+            ops,
+            vec![op(Function::Read, 1, None), op(Function::Write, 2, Some(3))]
+        );
+        assert_eq!(
+            serde_json::to_string(&ops).unwrap(),
+            r#"[["r",1,null],["w",2,3]]"#
+        );
+    }
+}